@@ -10,14 +10,12 @@ fn main() {
     .unwrap();
     // let seq = disseqt::load_dsv("examples/3DSnapshotGRE_Comparision_E_0_64_64_8_alternating_fully_sampled/SimulationProtocol", Some(64)).unwrap();
 
-    let mut t = 0.0;
-    while let Some((pulse_start, pulse_end)) = seq.encounter(t, EventType::RfPulse) {
+    for (pulse_start, pulse_end) in seq.encounters(EventType::RfPulse, 0.0, f64::INFINITY) {
         let Moment { pulse, .. } = seq.integrate_one(pulse_start, pulse_end);
 
         println!(
             "[{pulse_start}: {}ms]: {pulse:?}",
             (pulse_end - pulse_start) * 1e3
         );
-        t = pulse_end;
     }
 }
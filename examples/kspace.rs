@@ -7,11 +7,10 @@ fn main() {
     let fov = seq.fov().unwrap_or((1.0, 1.0, 1.0));
 
     let mut kspace: Vec<Vec<(f64, f64, f64)>> = Vec::new();
-    let mut t = 0.0;
 
-    while let Some((pulse_start, pulse_end)) = seq.encounter(t, EventType::RfPulse) {
+    for (pulse_start, pulse_end) in seq.encounters(EventType::RfPulse, 0.0, f64::INFINITY) {
         // Start integrating at the center of the pulse
-        t = (pulse_start + pulse_end) / 2.0;
+        let t = (pulse_start + pulse_end) / 2.0;
 
         let mut kx = 0.0;
         let mut ky = 0.0;
@@ -19,19 +18,16 @@ fn main() {
         kspace.push(Vec::new());
         let line = kspace.last_mut().unwrap();
 
+        // Integrate from the pulse center to every ADC sample in one go
         let (_, adc_end) = seq.encounter(t, EventType::Adc).unwrap();
-        while let Some(next_adc) = seq.next_event(t + 1e-6, EventType::Adc) {
-            if next_adc > adc_end {
-                break;
-            }
-
-            // The integration code currently is too slow.
-            let moment = seq.integrate_one(t, next_adc);
-            t = next_adc;
-
-            kx += moment.gradient.x * fov.0;
-            ky += moment.gradient.y * fov.1;
-            kz += moment.gradient.z * fov.2;
+        let mut time = vec![t];
+        time.extend(seq.events_iter(EventType::Adc, t, adc_end));
+        let moments = seq.integrate(&time);
+
+        for i in 0..moments.len() {
+            kx += moments.gradient.x[i] * fov.0;
+            ky += moments.gradient.y[i] * fov.1;
+            kz += moments.gradient.z[i] * fov.2;
             line.push((kx, ky, kz));
         }
     }
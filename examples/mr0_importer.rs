@@ -1,7 +1,7 @@
 // This is only a mock importer to test performance, we don't have a mr0-rs (yet?)
 // It mimicks the python example importer
 
-use disseqt::EventType;
+use disseqt::{EventType, PulseUsage};
 
 fn import_pulseq(path: &str) -> mr0::Sequence {
     // let parser = disseqt::load_pulseq(path).unwrap();
@@ -9,22 +9,25 @@ fn import_pulseq(path: &str) -> mr0::Sequence {
     let mut seq = mr0::Sequence::default();
     let mut t = 0.0;
 
-    fn pulse_usage(angle: f64) -> mr0::PulseUsage {
-        if angle.abs() < 100.0 * std::f64::consts::PI / 180.0 {
-            mr0::PulseUsage::Excit
-        } else {
-            mr0::PulseUsage::Refoc
+    fn pulse_usage(usage: PulseUsage) -> mr0::PulseUsage {
+        match usage {
+            PulseUsage::Excitation => mr0::PulseUsage::Excit,
+            PulseUsage::Refocusing => mr0::PulseUsage::Refoc,
+            _ => mr0::PulseUsage::Undefined,
         }
     }
 
     let fov = parser.fov().unwrap_or((1.0, 1.0, 1.0));
 
     while let Some((pulse_start, pulse_end)) = parser.encounter(t, EventType::RfPulse) {
-        let rep_start = (pulse_start + pulse_end) / 2.0;
+        // Repetitions start at the center of the pulse, which is not the
+        // midpoint for asymmetric pulses
+        let pulse_info = parser.pulse_info((pulse_start, pulse_end));
+        let rep_start = pulse_info.center;
 
         // Calculate end of repetition
         let rep_end = match parser.encounter(pulse_end, EventType::RfPulse) {
-            Some((start, end)) => (start + end) / 2.0,
+            Some(next_pulse) => parser.pulse_info(next_pulse).center,
             None => parser.duration(),
         };
 
@@ -42,7 +45,7 @@ fn import_pulseq(path: &str) -> mr0::Sequence {
         let moment = parser.integrate_one(pulse_start, pulse_end);
         rep.pulse.angle = moment.pulse.angle;
         rep.pulse.phase = moment.pulse.phase;
-        rep.pulse.usage = pulse_usage(moment.pulse.angle);
+        rep.pulse.usage = pulse_usage(pulse_info.usage);
 
         let abs_times: Vec<f64> = std::iter::once(&rep_start)
             .chain(adc_times.iter())
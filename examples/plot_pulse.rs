@@ -6,16 +6,10 @@ fn main() {
 
     let (t_start, t_end) = seq.encounter(0.0, EventType::RfPulse).unwrap();
 
-    let mut t = t_start;
-    let mut sample_count = 0;
-    while let Some(t_sample) = seq.next_event(t, EventType::RfPulse) {
-        if t_sample > t_end {
-            break;
-        } else {
-            t = t_sample + 1e-7; // Advance a bit past the sample
-            sample_count += 1;
-        }
-    }
+    let sample_count = seq
+        .events_iter(EventType::RfPulse, t_start, f64::INFINITY)
+        .take_while(|&t_sample| t_sample <= t_end)
+        .count();
     println!("First pulse: [{t_start}..{t_end}] s, {sample_count} Events");
 
     // Sample the pulse
@@ -0,0 +1,137 @@
+use crate::{
+    AdcReadout, EventType, ExtensionEvent, Labels, Metadata, MomentVec, PulseInfo, Rasters,
+    RfOptions, RfPulseMomentVec, SampleVec, SoftDelay, ValidationIssue,
+};
+
+/// This trait is implemented by all backends and provides the basic functions
+/// on which the public disseqt API is built upon.
+///
+/// It is public so that other crates can add their own file formats: implement
+/// it and wrap the backend with [`Sequence::from_backend`](crate::Sequence::from_backend).
+/// All times are in seconds and all values in the SI units documented on the
+/// sample and moment types. Methods with a default implementation are built on
+/// top of the required ones and only need to be overridden for performance.
+pub trait Backend: Send + Sync {
+    /// Return the FOV of the Sequence, if it is available
+    fn fov(&self) -> Option<(f64, f64, f64)>;
+
+    /// General information about the sequence. The default implementation
+    /// only contains the FOV.
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            fov: self.fov(),
+            ..Default::default()
+        }
+    }
+
+    /// Native time resolution of the channels, if the format has fixed rasters
+    fn rasters(&self) -> Option<Rasters> {
+        None
+    }
+
+    /// Duration of the MRI sequence: no samples, blocks, etc. exist outside
+    /// of the time range [0, duration()]
+    fn duration(&self) -> f64;
+
+    /// Returns all events of the given type in the given duration.
+    /// t_start is inclusive, t_end is exclusive. If a max_count is given and
+    /// reached, there might be more events in the time span that are not returned.
+    fn events(&self, ty: EventType, t_start: f64, t_end: f64, max_count: usize) -> Vec<f64> {
+        self.events_iter(ty, t_start, t_end)
+            .take(max_count)
+            .collect()
+    }
+
+    /// Lazily iterates over all events of the given type in the given duration,
+    /// in strictly increasing order. t_start is inclusive, t_end is exclusive.
+    fn events_iter(
+        &self,
+        ty: EventType,
+        t_start: f64,
+        t_end: f64,
+    ) -> Box<dyn Iterator<Item = f64> + '_>;
+
+    /// Returns the time range of the next encounter of the given type.
+    /// If `t_start` is inside of a block, this block is not returned: only
+    /// blocks **starting** after (or exactly on) `t_start` are considered.
+    /// TODO: EventType should be the first parameter
+    fn encounter(&self, t_start: f64, ty: EventType) -> Option<(f64, f64)> {
+        self.encounters(ty, t_start, f64::INFINITY).next()
+    }
+
+    /// Lazily iterates over the time ranges of all encounters of the given type
+    /// that start in the given duration, ordered by their start time.
+    /// t_start is inclusive, t_end is exclusive.
+    fn encounters(
+        &self,
+        ty: EventType,
+        t_start: f64,
+        t_end: f64,
+    ) -> Box<dyn Iterator<Item = (f64, f64)> + '_>;
+
+    /// Returns the labels (LIN, PAR, SLC, ...) that are active at time `t`,
+    /// or `None` if the format doesn't support labels.
+    fn labels_at(&self, t: f64) -> Option<Labels> {
+        let _ = t;
+        None
+    }
+
+    /// Returns all extensions attached to blocks starting in the given
+    /// duration, ordered by time. Empty if the format has no extensions.
+    fn extensions(&self, t_start: f64, t_end: f64) -> Vec<ExtensionEvent> {
+        let _ = (t_start, t_end);
+        Vec::new()
+    }
+
+    /// Returns the ADC readouts whose encounters start in [t_start, t_end),
+    /// ordered by time. Empty if the format doesn't describe its readouts.
+    fn adc_readouts(&self, t_start: f64, t_end: f64) -> Box<dyn Iterator<Item = AdcReadout> + '_> {
+        let _ = (t_start, t_end);
+        Box::new(std::iter::empty())
+    }
+
+    /// Returns the ADC readout whose encounter contains `t`, if any
+    fn adc_readout_at(&self, t: f64) -> Option<AdcReadout> {
+        let _ = t;
+        None
+    }
+
+    /// Returns usage and center of the RF pulse with the given encounter time
+    /// span, or `None` if the format doesn't store them. The caller then
+    /// estimates them from the pulse, see `Sequence::pulse_info`.
+    fn pulse_info(&self, pulse: (f64, f64)) -> Option<PulseInfo> {
+        let _ = pulse;
+        None
+    }
+
+    /// Returns the soft delays of the sequence ordered by their id, or an
+    /// empty list if the format doesn't support them.
+    fn soft_delays(&self) -> Vec<SoftDelay> {
+        Vec::new()
+    }
+
+    /// Checks the timing and consistency of the sequence. Empty if no problems
+    /// were found or the format has no checks.
+    fn validate(&self) -> Vec<ValidationIssue> {
+        Vec::new()
+    }
+
+    /// Samples the sequence at the given time points. Clears `samples` and
+    /// fills it with exactly one sample per time point. Trigger samples can
+    /// be left out, `Sequence` fills them in as inactive.
+    fn sample(&self, time: &[f64], samples: &mut SampleVec);
+
+    /// Integrates over the n-1 time intervalls given by the list of n time points.
+    /// Clears `moments` and fills it with exactly n-1 moments.
+    fn integrate(&self, time: &[f64], moments: &mut MomentVec);
+
+    /// Integrates the RF pulses like `integrate`, but off-resonant as given by
+    /// the options. Clears `moments` and fills it with exactly n-1 moments.
+    /// The default implementation ignores the options.
+    fn integrate_rf(&self, time: &[f64], options: &RfOptions, moments: &mut RfPulseMomentVec) {
+        let _ = options;
+        let mut all = MomentVec::default();
+        self.integrate(time, &mut all);
+        *moments = all.pulse;
+    }
+}
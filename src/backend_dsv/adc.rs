@@ -1,11 +1,6 @@
-use std::path::Path;
+use crate::{backend_dsv::trigger::Trigger, AdcReadout, Error};
 
-use crate::backend_dsv::trigger::Trigger;
-
-use super::{
-    helpers::{decompress_shape, DsvFile},
-    Error,
-};
+use super::helpers::DsvSource;
 
 pub struct Adc {
     /// Adc enabled or not
@@ -14,8 +9,6 @@ pub struct Adc {
     pub phase: Vec<f64>,
     /// Sample time step in seconds
     pub time_step: f64,
-    /// Frequency in Hz
-    pub frequency: f64,
     /// Location of adc blocks
     events: Trigger,
     /// Used to calculate the dwell time used in ADC blocks
@@ -23,17 +16,19 @@ pub struct Adc {
 }
 
 impl Adc {
-    pub fn load<P: AsRef<Path>>(path: P, resolution: Option<usize>) -> Result<Self, Error> {
-        let active = AdcRaw::load(&path, "ADC")?;
-        let phase = AdcRaw::load(&path, "NC1")?;
+    pub fn load(source: &DsvSource, resolution: Option<usize>) -> Result<Self, Error> {
+        let active = AdcRaw::load(source, "ADC")?;
+        let phase = AdcRaw::load(source, "NC1")?;
 
-        // TODO: return errors instead of panicking
-        assert_eq!(active.data.len(), phase.data.len());
-        assert_eq!(active.time_step, phase.time_step);
+        if active.data.len() != phase.data.len() {
+            return Err(active.mismatch(&phase, "sample count"));
+        }
+        if active.time_step != phase.time_step {
+            return Err(active.mismatch(&phase, "time step"));
+        }
 
-        let events = Trigger::new(&active.data);
+        let events = Trigger::new(&active.data, &active.name)?;
         let time_step = active.time_step;
-        let frequency = active.frequency.unwrap_or(0.0);
         let phase = phase.data;
         let active = active.data.into_iter().map(|x| x > 0.5).collect();
 
@@ -42,7 +37,6 @@ impl Adc {
             phase,
             time_step,
             events,
-            frequency,
             resolution,
         })
     }
@@ -51,76 +45,112 @@ impl Adc {
         self.time_step * self.active.len() as f64
     }
 
-    pub fn encounter(&self, t_start: f64) -> Option<(f64, f64)> {
+    pub fn encounters(&self, t_start: f64, t_end: f64) -> impl Iterator<Item = (f64, f64)> + '_ {
         let i_start = (t_start / self.time_step).ceil() as usize;
-        let (i_start, i_end) = self.events.search(i_start)?;
 
-        Some((
-            i_start as f64 * self.time_step,
-            (i_end + 1) as f64 * self.time_step,
-        ))
+        self.events
+            .search(i_start)
+            .map(|(i_start, i_end)| {
+                (
+                    i_start as f64 * self.time_step,
+                    (i_end + 1) as f64 * self.time_step,
+                )
+            })
+            .take_while(move |&(start, _)| start < t_end)
     }
 
-    pub fn events(&self, t_start: f64, t_end: f64, max_count: usize) -> Vec<f64> {
+    pub fn events(&self, t_start: f64, t_end: f64) -> impl Iterator<Item = f64> + '_ {
         // TODO Naming: the events inside of the Trigger are blocks and ADC events = samples
         let i_start = (t_start / self.time_step).ceil() as usize;
         let i_end = (t_end / self.time_step).floor() as usize;
 
-        let mut samples = Vec::new();
-        for event in self.events.events(i_start, i_end) {
-            let a = i_start.max(event.0);
-            let b = i_end.min(event.1);
-
-            match self.resolution {
-                Some(res) => {
-                    let adc_start = a as f64 * self.time_step;
-                    let adc_end = (b + 1) as f64 * self.time_step;
-                    let dwell = (adc_end - adc_start) / res as f64;
-
-                    samples.extend(
-                        (0..res)
-                            .take(max_count - samples.len())
-                            .map(|i| adc_start + (i as f64 + 0.5) * dwell),
-                    );
-                }
-                None => {
-                    let step = (10e-6 / self.time_step).max(1.0) as usize;
-
-                    samples.extend(
-                        (a + step / 2..=b)
-                            .step_by(step)
-                            .take(max_count - samples.len())
-                            .map(|i| i as f64 * self.time_step),
-                    );
-                }
+        // Sample times only depend on the ADC block, not on the time range
+        let first = self.events.overlap_position(i_start);
+        self.events
+            .events(i_start, i_end)
+            .enumerate()
+            .flat_map(move |(i, event)| {
+                let readout = self.readout(first + i, event);
+                (0..readout.num_samples).map(move |i| readout.sample_time(i))
+            })
+            .filter(move |&t| t_start <= t && t < t_end)
+    }
+
+    /// All readouts whose ADC blocks start in the given time range
+    pub fn readouts(&self, t_start: f64, t_end: f64) -> impl Iterator<Item = AdcReadout> + '_ {
+        let i_start = (t_start / self.time_step).ceil() as usize;
+        let first = self.events.search_position(i_start);
+
+        self.events
+            .search(i_start)
+            .take_while(move |&(start, _)| (start as f64 * self.time_step) < t_end)
+            .enumerate()
+            .map(move |(i, event)| self.readout(first + i, event))
+    }
+
+    /// The readout whose ADC block contains `t`
+    pub fn readout_at(&self, t: f64) -> Option<AdcReadout> {
+        let i = (t / self.time_step).round() as usize;
+        let index = self.events.search_position(i + 1).checked_sub(1)?;
+        let event = self.events.get(index)?;
+        (i <= event.1).then(|| self.readout(index, event))
+    }
+
+    /// Readout of the ADC block with the given start and end sample index.
+    /// Samples are either evenly distributed over the block (resolution)
+    /// or placed on a 10 us raster.
+    fn readout(&self, index: usize, (a, b): (usize, usize)) -> AdcReadout {
+        let adc_start = a as f64 * self.time_step;
+        let adc_end = (b + 1) as f64 * self.time_step;
+
+        let (start, num_samples, dwell) = match self.resolution {
+            Some(res) => {
+                let dwell = (adc_end - adc_start) / res as f64;
+                (adc_start + 0.5 * dwell, res, dwell)
+            }
+            None => {
+                let step = (10e-6 / self.time_step).max(1.0) as usize;
+                let first = a + step / 2;
+                let count = if first <= b {
+                    (b - first) / step + 1
+                } else {
+                    0
+                };
+                (
+                    first as f64 * self.time_step,
+                    count,
+                    step as f64 * self.time_step,
+                )
             }
+        };
+
+        AdcReadout {
+            index,
+            start,
+            num_samples,
+            dwell,
+            phase: self.phase[a],
+            // The NOMINALFREQUENCY of the file is the carrier, any offset is
+            // part of the phase
+            frequency: 0.0,
         }
-
-        samples
     }
 }
 
 pub struct AdcRaw {
     pub data: Vec<f64>,
     time_step: f64,
-    frequency: Option<f64>,
+    name: String,
 }
 impl AdcRaw {
-    pub fn load<P: AsRef<Path>>(path: P, which_dsv: &str) -> Result<Self, Error> {
-        let dsv = DsvFile::load(&path, which_dsv)?;
-
-        // TODO: don't unwrap but return the parse errors
-        // TODO: do the same with key errors (currently panics)
-        let num_samples: usize = dsv.definitions["SAMPLES"].parse().unwrap();
-        let amp_step = dsv.amp_step(None);
-        let time_step = dsv.time_step();
+    pub fn load(source: &DsvSource, which_dsv: &str) -> Result<Self, Error> {
+        let dsv = source.load(which_dsv)?;
 
-        let frequency = dsv
-            .definitions
-            .get("NOMINALFREQUENCY")
-            .map(|def| def.parse::<f64>().unwrap());
+        let amp_step = dsv.amp_step(None)?;
+        let time_step = dsv.time_step()?;
 
-        let data: Vec<f64> = decompress_shape(dsv.values, num_samples)
+        let data: Vec<f64> = dsv
+            .decompress()?
             .into_iter()
             .map(|x| x as f64 * amp_step)
             .collect();
@@ -128,7 +158,73 @@ impl AdcRaw {
         Ok(Self {
             data,
             time_step,
-            frequency,
+            name: dsv.name,
         })
     }
+
+    fn mismatch(&self, other: &Self, property: &'static str) -> Error {
+        Error::ChannelMismatch {
+            file_a: self.name.clone(),
+            file_b: other.name.clone(),
+            property,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Adc, Trigger};
+    use assert2::check;
+
+    /// Two ADC blocks of 40 samples each, starting at 100 us and 170 us
+    fn adc(resolution: Option<usize>) -> Adc {
+        let data: Vec<f64> = [(100, 0.0), (40, 1.0), (30, 0.0), (40, 1.0), (20, 0.0)]
+            .into_iter()
+            .flat_map(|(count, x)| vec![x; count])
+            .collect();
+        Adc {
+            active: data.iter().map(|&x| x > 0.5).collect(),
+            phase: vec![0.5; data.len()],
+            time_step: 1e-6,
+            events: Trigger::new(&data, "ADC").unwrap(),
+            resolution,
+        }
+    }
+
+    #[test]
+    fn readouts() {
+        let adc = adc(Some(8));
+        let readouts: Vec<_> = adc.readouts(0.0, f64::INFINITY).collect();
+        check!(readouts.len() == 2);
+        check!(readouts[1].index == 1);
+        check!(readouts[1].num_samples == 8);
+        check!((readouts[1].dwell - 5e-6).abs() < 1e-15);
+        check!((readouts[1].start - 172.5e-6).abs() < 1e-15);
+        check!(readouts[1].phase == 0.5);
+        check!(readouts[1].frequency == 0.0);
+        check!(adc.readouts(150e-6, f64::INFINITY).next().unwrap().index == 1);
+
+        // Every sample maps back to its readout
+        let samples: Vec<f64> = adc.events(0.0, f64::INFINITY).collect();
+        check!(samples.len() == 16);
+        for (i, &t) in samples.iter().enumerate() {
+            let readout = adc.readout_at(t).unwrap();
+            check!(readout.index == i / 8);
+            check!(readout.sample_index(t) == Some(i % 8));
+        }
+        check!(adc.readout_at(150e-6).is_none());
+
+        // Sample times don't depend on the requested time range
+        let partial: Vec<f64> = adc.events(180e-6, f64::INFINITY).collect();
+        check!(partial[..] == samples[10..]);
+    }
+
+    #[test]
+    fn readouts_without_resolution() {
+        // Samples on a 10 us raster, centered in the raster cells
+        let readout = adc(None).readouts(0.0, f64::INFINITY).next().unwrap();
+        check!(readout.num_samples == 4);
+        check!((readout.start - 105e-6).abs() < 1e-15);
+        check!((readout.dwell - 10e-6).abs() < 1e-15);
+    }
 }
@@ -1,15 +1,14 @@
-use std::path::Path;
+use crate::backend_dsv::helpers::DsvSource;
 
-use crate::backend_dsv::helpers::DsvFile;
-
-use super::{helpers::decompress_shape, trigger::Trigger, Error};
+use super::trigger::Trigger;
+use crate::{util, Error, Interpolation};
 
 pub struct Grad {
     // TODO: this is written in the file, should convert it into something else
     /// Currently: mT/m
     amplitude: Vec<f64>,
     /// Sample time step in seconds
-    time_step: f64,
+    pub time_step: f64,
     /// Location of gradients
     events: Trigger,
 }
@@ -17,21 +16,19 @@ pub struct Grad {
 // TODO: the impls are very similar to RF - maybe factor out something?
 
 impl Grad {
-    pub fn load<P: AsRef<Path>>(path: P, which_dsv: &str) -> Result<Self, Error> {
-        let dsv = DsvFile::load(path, which_dsv)?;
+    pub fn load(source: &DsvSource, which_dsv: &str) -> Result<Self, Error> {
+        let dsv = source.load(which_dsv)?;
 
-        // TODO: don't unwrap but return the parse errors
-        // TODO: do the same with key errors (currently panics)
-        let num_samples: usize = dsv.definitions["SAMPLES"].parse().unwrap();
-        let time_step = dsv.time_step();
-        let amp_step = dsv.amp_step(None);
+        let time_step = dsv.time_step()?;
+        let amp_step = dsv.amp_step(None)?;
 
-        let amplitude: Vec<f64> = decompress_shape(dsv.values, num_samples)
+        let amplitude: Vec<f64> = dsv
+            .decompress()?
             .into_iter()
             .map(|x| x as f64 * amp_step)
             .collect();
 
-        let events = Trigger::new(&amplitude);
+        let events = Trigger::new(&amplitude, &dsv.name)?;
 
         Ok(Self {
             amplitude,
@@ -44,71 +41,88 @@ impl Grad {
         self.time_step * self.amplitude.len() as f64
     }
 
-    pub fn events(&self, t_start: f64, t_end: f64, max_count: usize) -> Vec<f64> {
+    pub fn events(&self, t_start: f64, t_end: f64) -> impl Iterator<Item = f64> + '_ {
         // Simple solution: we are on a fixed raster - return that.
         // Could only return events within encounters, but we assume that
         // The user checks where those encounters are themselves.
         let i_start = (t_start / self.time_step).ceil() as usize;
         let i_end = (t_end / self.time_step).ceil() as usize;
 
-        (i_start..i_end)
-            .take(max_count)
-            .map(|i| i as f64 * self.time_step)
-            .collect()
+        (i_start..i_end).map(|i| i as f64 * self.time_step)
     }
 
-    pub fn encounter(&self, t_start: f64) -> Option<(f64, f64)> {
+    pub fn encounters(&self, t_start: f64, t_end: f64) -> impl Iterator<Item = (f64, f64)> + '_ {
         let i_start = (t_start / self.time_step).ceil() as usize;
-        let (i_start, i_end) = self.events.search(i_start)?;
 
-        Some((
-            i_start as f64 * self.time_step,
-            (i_end + 1) as f64 * self.time_step,
-        ))
+        self.events
+            .search(i_start)
+            .map(|(i_start, i_end)| {
+                (
+                    i_start as f64 * self.time_step,
+                    (i_end + 1) as f64 * self.time_step,
+                )
+            })
+            .take_while(move |&(start, _)| start < t_end)
     }
 
-    pub fn sample(&self, t: f64) -> f64 {
-        if t < 0.0 {
-            0.0
-        } else {
-            let index = (t / self.time_step).round() as usize;
-            self.amplitude.get(index).cloned().unwrap_or(0.0)
-        }
+    pub fn sample(&self, t: f64, interpolation: Interpolation) -> f64 {
+        interpolation.sample(&self.amplitude, t / self.time_step)
     }
 
+    /// Exact integral of the linear interpolation between the samples, see
+    /// [`util::integrate_grad_shape`]
     pub fn integrate(&self, t_start: f64, t_end: f64) -> f64 {
         // TODO: this is not performant for integrations over long time periods
         // because it will sum up all zeros of the empty space between pulses
-        let i_start = (t_start / self.time_step).floor() as usize;
-        let mut grad = 0.0;
-
-        for i in i_start..self.amplitude.len() {
-            let t = i as f64 * self.time_step;
-
-            // Skip samples before t_start, quit when reaching t_end
-            if t + self.time_step < t_start {
-                continue;
-            }
-            if t_end <= t {
-                break;
-            }
-
-            // We could do the clamping for all samples, but when integrating
-            // over many samples, it seems to be very sensitive to accumulating
-            // errors. Only doing it in the edge cases is much more robust.
-            let dur = if t_start <= t && t + self.time_step <= t_end {
-                self.time_step
-            } else {
-                // Clamp the sample intervall to the integration intervall
-                let t0 = t.clamp(t_start, t_end);
-                let t1 = (t + self.time_step).clamp(t_start, t_end);
-                t1 - t0
-            };
-
-            // TODO: units?
-            grad += self.amplitude[i] * dur;
+        self.time_step
+            * util::integrate_grad_shape(
+                &self.amplitude,
+                util::extrapolated_edges(&self.amplitude),
+                t_start / self.time_step,
+                t_end / self.time_step,
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Grad, Interpolation, Trigger};
+    use assert2::check;
+
+    /// The samples 1, 2, 3, 4 on a 10 us raster, followed by zeros
+    fn grad() -> Grad {
+        let mut amplitude = vec![1.0, 2.0, 3.0, 4.0];
+        amplitude.resize(16, 0.0);
+        Grad {
+            events: Trigger::new(&amplitude, "GRX").unwrap(),
+            amplitude,
+            time_step: 10e-6,
         }
+    }
+
+    #[test]
+    fn sample_positions() {
+        // Sample i holds for [i, i + 1) raster cells, not [i - 0.5, i + 0.5)
+        let grad = grad();
+        let sample = |t: f64| grad.sample(t, Interpolation::default());
+        check!(sample(4e-6) == 1.0);
+        check!(sample(6e-6) == 1.0);
+        check!(sample(14e-6) == 2.0);
+        check!(sample(39e-6) == 4.0);
+        check!(sample(41e-6) == 0.0);
+    }
 
-        grad
+    #[test]
+    fn integrate() {
+        let grad = grad();
+        let integrate = |t_start: f64, t_end: f64| grad.integrate(t_start, t_end) / 10e-6;
+
+        // Extrapolated to 0.5 at the start of the channel, like pulseq does
+        check!((integrate(0.0, 10e-6) - 1.0).abs() < 1e-12);
+        check!((integrate(-1.0, 10e-6) - 1.0).abs() < 1e-12);
+        // Ramps down to the following zero between the raster centers
+        check!((integrate(30e-6, 50e-6) - 3.875).abs() < 1e-12);
+        check!((integrate(0.0, 1.0) - 9.875).abs() < 1e-12);
+        check!((integrate(0.0, 23e-6) + integrate(23e-6, 1.0) - 9.875).abs() < 1e-12);
     }
 }
@@ -1,138 +1,270 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, path::Path, str::FromStr};
 
-use super::Error;
+use crate::{util::decompress_shape, Error};
+
+/// Where the DSV channel files are read from
+pub enum DsvSource<'a> {
+    /// Path to the DSV stem, channel files are `<stem>_<CHANNEL>.dsv`
+    Path(&'a Path),
+    /// Contents of the channel files, indexed by channel name (`"RFD"`, `"GRX"`, ...)
+    Buffers(&'a HashMap<String, Vec<u8>>),
+}
+
+impl DsvSource<'_> {
+    pub fn load(&self, which_dsv: &str) -> Result<DsvFile, Error> {
+        match self {
+            DsvSource::Path(path) => DsvFile::load(path, which_dsv),
+            DsvSource::Buffers(buffers) => {
+                let buf = buffers
+                    .get(which_dsv)
+                    .ok_or_else(|| Error::MissingChannel(which_dsv.to_owned()))?;
+                DsvFile::parse(which_dsv.to_owned(), &String::from_utf8_lossy(buf))
+            }
+        }
+    }
+}
 
 pub struct DsvFile {
+    /// Used to identify the file in error messages
+    pub name: String,
     pub definitions: HashMap<String, String>,
     pub values: Vec<i64>,
 }
 
 impl DsvFile {
     pub fn load<P: AsRef<Path>>(path: P, which_dsv: &str) -> Result<Self, Error> {
-        let file_name = path.as_ref().file_stem().unwrap().to_str().unwrap();
+        // The stem is a prefix of the channel file names, not a file with an
+        // extension: `my.v2` -> `my.v2_RFD.dsv`
+        let file_name = path
+            .as_ref()
+            .file_name()
+            .ok_or_else(|| Error::FileNotFound(path.as_ref().to_owned()))?
+            .to_string_lossy();
         let file_path = path
             .as_ref()
             .with_file_name(format!("{file_name}_{which_dsv}.dsv"));
         let file_buf =
-            std::fs::read(file_path.clone()).map_err(|_| Error::FileNotFound(file_path))?;
+            std::fs::read(file_path.clone()).map_err(|_| Error::FileNotFound(file_path.clone()))?;
         let file_str = String::from_utf8_lossy(&file_buf);
 
-        let definitions_raw = file_str
-            .split('[')
-            .find(|s| s.starts_with("DEFINITIONS"))
-            .unwrap();
-
-        let definitions: HashMap<_, _> = definitions_raw
-            .lines()
-            .skip(1)
-            .filter(|l| !l.is_empty())
-            .map_while(|def| def.split_once('=')) // stop at first non-def
-            .map(|(key, val)| (key.trim().to_owned(), val.trim().to_owned()))
-            .collect();
-
-        let values_raw = file_str
-            .split('[')
-            .find(|s| s.starts_with("VALUES"))
-            .unwrap();
-
-        let values: Vec<i64> = values_raw
-            .lines()
-            .skip(1)
-            .map_while(|s| s.parse().ok())
-            .collect();
+        Self::parse(file_path.display().to_string(), &file_str)
+    }
+
+    fn parse(name: String, file_str: &str) -> Result<Self, Error> {
+        let mut definitions = None;
+        let mut values = None;
+        let mut section = "";
+
+        for (i, line) in file_str.lines().enumerate() {
+            let line = line.trim();
+            if let Some(header) = line.strip_prefix('[') {
+                section = header.trim_end_matches(']');
+                match section {
+                    "DEFINITIONS" => definitions = Some(HashMap::new()),
+                    "VALUES" => values = Some(Vec::new()),
+                    _ => (),
+                }
+                continue;
+            }
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            match (section, definitions.as_mut(), values.as_mut()) {
+                ("DEFINITIONS", Some(definitions), _) => {
+                    if let Some((key, val)) = line.split_once('=') {
+                        definitions.insert(key.trim().to_owned(), val.trim().to_owned());
+                    }
+                }
+                ("VALUES", _, Some(values)) => {
+                    let value = line.parse().map_err(|_| Error::InvalidLine {
+                        file: name.clone(),
+                        line: i + 1,
+                        content: line.to_owned(),
+                    })?;
+                    values.push(value);
+                }
+                _ => (),
+            }
+        }
+
+        let missing_section = |section: &str| Error::MissingSection {
+            file: name.clone(),
+            section: section.to_owned(),
+        };
+        let definitions = definitions.ok_or_else(|| missing_section("DEFINITIONS"))?;
+        let values = values.ok_or_else(|| missing_section("VALUES"))?;
 
         Ok(Self {
+            name,
             definitions,
             values,
         })
     }
 
-    // TODO: don't unwrap but return the parse errors
-    // TODO: do the same with key errors (currently panics)
+    pub fn get(&self, key: &str) -> Result<&str, Error> {
+        self.definitions
+            .get(key)
+            .map(|s| s.as_str())
+            .ok_or_else(|| Error::MissingKey {
+                file: self.name.clone(),
+                key: key.to_owned(),
+            })
+    }
 
-    pub fn time_step(&self) -> f64 {
-        let time_unit = hori_unit_si_factor(&self.definitions["HORIUNITNAME"]);
-        let time_step = self.definitions["HORIDELTA"].parse::<f64>().unwrap();
-        time_step * time_unit
+    pub fn parse_def<T: FromStr>(&self, key: &str) -> Result<T, Error> {
+        let value = self.get(key)?;
+        value.parse().map_err(|_| Error::InvalidValue {
+            file: self.name.clone(),
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
     }
 
-    pub fn amp_step(&self, ref_voltage: Option<f64>) -> f64 {
-        let amp_unit = vert_unit_si_factor(&self.definitions["VERTUNITNAME"], ref_voltage);
-        let amp_step = 1.0 / self.definitions["VERTFACTOR"].parse::<f64>().unwrap();
-        amp_step * amp_unit
+    pub fn time_step(&self) -> Result<f64, Error> {
+        let unit = self.get("HORIUNITNAME")?;
+        let time_unit = hori_unit_si_factor(unit).ok_or_else(|| Error::UnknownUnit {
+            file: self.name.clone(),
+            unit: unit.to_owned(),
+        })?;
+        let time_step: f64 = self.parse_def("HORIDELTA")?;
+        Ok(time_step * time_unit)
+    }
+
+    pub fn amp_step(&self, ref_voltage: Option<f64>) -> Result<f64, Error> {
+        let unit = self.get("VERTUNITNAME")?;
+        let amp_unit = vert_unit_si_factor(unit, ref_voltage).ok_or_else(|| {
+            if unit == "Volt" {
+                Error::MissingRefVoltage {
+                    file: self.name.clone(),
+                }
+            } else {
+                Error::UnknownUnit {
+                    file: self.name.clone(),
+                    unit: unit.to_owned(),
+                }
+            }
+        })?;
+        let amp_step = 1.0 / self.parse_def::<f64>("VERTFACTOR")?;
+        Ok(amp_step * amp_unit)
+    }
+
+    /// Decompresses the `[VALUES]` section, checking the length against `SAMPLES`
+    pub fn decompress(&self) -> Result<Vec<i64>, Error> {
+        let num_samples: usize = self.parse_def("SAMPLES")?;
+        decompress_shape(&self.values, num_samples).map_err(|actual| Error::SampleCount {
+            file: self.name.clone(),
+            expected: num_samples,
+            actual,
+        })
     }
 }
 
-fn vert_unit_si_factor(unit: &str, ref_voltage: Option<f64>) -> f64 {
+fn vert_unit_si_factor(unit: &str, ref_voltage: Option<f64>) -> Option<f64> {
     const GAMMA: f64 = 42_576_385.43;
     const PI: f64 = std::f64::consts::PI;
     // 1ms Block pulse at ref_voltage = 180° -> ref_voltage = 500 Hz rotation
 
     match unit {
         // SI: [Hz/m]
-        "T/m" => GAMMA,
-        "mT/m" => 1e-3 * GAMMA,
+        "T/m" => Some(GAMMA),
+        "mT/m" => Some(1e-3 * GAMMA),
         // SI: [rad]
-        "Degree" => PI / 180.0,
+        "Degree" => Some(PI / 180.0),
         // SI: [Hz]
-        "Volt" => 500.0 / ref_voltage.unwrap(), // optional if unit is not Volts
+        "Volt" => ref_voltage.map(|v| 500.0 / v), // optional if unit is not Volts
         // No unit (ADC)
-        "-" => 1.0,
-        _ => panic!("Unknown amplitude unit {unit:?}"),
+        "-" => Some(1.0),
+        _ => None,
     }
 }
 
-fn hori_unit_si_factor(unit: &str) -> f64 {
+fn hori_unit_si_factor(unit: &str) -> Option<f64> {
     match unit {
-        "s" => 1e0,
-        "ms" => 1e-3,
-        "µs" | "μs" | "�s" | "us" => 1e-6,
-        "ns" => 1e-9,
-        _ => panic!("Unknown time unit {unit:?}"),
+        "s" => Some(1e0),
+        "ms" => Some(1e-3),
+        "µs" | "μs" | "�s" | "us" => Some(1e-6),
+        "ns" => Some(1e-9),
+        _ => None,
     }
 }
 
-pub fn decompress_shape(samples: Vec<i64>, num_samples: usize) -> Vec<i64> {
-    // First, decompress into the deriviate of the shape
-    let mut deriv = Vec::with_capacity(num_samples);
-
-    // The two samples before the current one, to detect RLE
-    let mut a = i64::MIN;
-    let mut b = i64::MAX;
-    // After a detected RLE, skip the RLE check for two samples
-    let mut skip = 0;
-
-    for sample in samples.into_iter() {
-        if a == b && skip == 0 {
-            skip = 2;
-            for _ in 0..sample as usize {
-                deriv.push(b);
-            }
-        } else {
-            if skip > 0 {
-                skip -= 1;
-            }
-            deriv.push(sample);
-        }
+#[cfg(test)]
+mod tests {
+    use super::{decompress_shape, DsvFile, DsvSource};
+    use crate::Error;
+    use assert2::check;
+    use std::collections::HashMap;
+
+    const HEADER: &str = "[DEFINITIONS]\nSAMPLES=4\nHORIDELTA=10\nHORIUNITNAME=us\nVERTFACTOR=1000\nVERTUNITNAME=mT/m\n";
 
-        a = b;
-        b = sample;
+    #[test]
+    fn parse_valid() {
+        let dsv =
+            DsvFile::parse("test".into(), &format!("{HEADER}[VALUES]\n1\n0\n1\n0\n")).unwrap();
+        check!(dsv.decompress().unwrap() == vec![1, 1, 2, 2]);
+        check!((dsv.time_step().unwrap() - 1e-5).abs() < 1e-15);
     }
 
-    if deriv.len() != num_samples {
-        panic!(
-            "Wrong decompressed length: got {}, expected {}",
-            deriv.len(),
-            num_samples
-        );
+    #[test]
+    fn invalid_line() {
+        let result = DsvFile::parse("test".into(), &format!("{HEADER}[VALUES]\n1\nx\n"));
+        check!(let Err(Error::InvalidLine { line: 9, .. }) = result);
     }
 
-    // Then, do a cumultative sum to get the shape
-    deriv
-        .into_iter()
-        .scan(0, |acc, x| {
-            *acc += x;
-            Some(*acc)
-        })
-        .collect()
+    #[test]
+    fn missing_and_invalid_definitions() {
+        let dsv =
+            DsvFile::parse("test".into(), "[DEFINITIONS]\nHORIDELTA=abc\n[VALUES]\n").unwrap();
+        check!(let Err(Error::MissingKey { .. }) = dsv.decompress());
+        check!(let Err(Error::MissingKey { .. }) = dsv.time_step());
+
+        let dsv = DsvFile::parse(
+            "test".into(),
+            "[DEFINITIONS]\nHORIDELTA=abc\nHORIUNITNAME=s\n[VALUES]\n",
+        )
+        .unwrap();
+        check!(let Err(Error::InvalidValue { .. }) = dsv.time_step());
+
+        let dsv = DsvFile::parse(
+            "test".into(),
+            "[DEFINITIONS]\nVERTFACTOR=1\nVERTUNITNAME=Volt\n[VALUES]\n",
+        )
+        .unwrap();
+        check!(let Err(Error::MissingRefVoltage { .. }) = dsv.amp_step(None));
+        check!(let Err(Error::UnknownUnit { .. }) = DsvFile::parse("test".into(), "[DEFINITIONS]\nVERTFACTOR=1\nVERTUNITNAME=G/cm\n[VALUES]\n").unwrap().amp_step(None));
+
+        check!(let Err(Error::MissingSection { .. }) = DsvFile::parse("test".into(), "[VALUES]\n1\n"));
+    }
+
+    #[test]
+    fn buffers() {
+        let buffers = HashMap::from([(
+            "GRX".to_owned(),
+            format!("{HEADER}[VALUES]\n1\n0\n1\n0\n").into_bytes(),
+        )]);
+        let source = DsvSource::Buffers(&buffers);
+        check!(source.load("GRX").unwrap().name == "GRX");
+        check!(let Err(Error::MissingChannel(_)) = source.load("GRY"));
+    }
+
+    #[test]
+    fn dotted_stem() {
+        let dir = std::env::temp_dir().join(format!("disseqt_stem_{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let content = format!("{HEADER}[VALUES]\n1\n0\n1\n0\n");
+        std::fs::write(dir.join("my.v2_GRX.dsv"), content).unwrap();
+
+        let dsv = DsvFile::load(dir.join("my.v2"), "GRX");
+        std::fs::remove_dir_all(dir).unwrap();
+        check!(dsv.unwrap().decompress().unwrap() == vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn wrong_sample_count() {
+        check!(decompress_shape(&[1, 2, 3], 4) == Err(3));
+        // RLE: the value 5 repeated 10 more times
+        check!(decompress_shape(&[5, 5, 10], 4) == Err(12));
+        check!(decompress_shape(&[5, 5, -1], 4) == Err(2));
+    }
 }
@@ -1,7 +1,9 @@
-use crate::{util, Backend, Moment};
-use std::fmt::Display;
-use std::path::{Path, PathBuf};
-use thiserror::Error;
+use crate::{
+    util, Backend, Error, Interpolation, Metadata, MomentVec, Rasters, RfOptions, RfPulseMomentVec,
+    SampleVec,
+};
+use helpers::DsvSource;
+use std::{collections::HashMap, path::Path};
 
 mod adc;
 mod grad;
@@ -9,39 +11,58 @@ mod helpers;
 mod rf;
 mod trigger;
 
-#[derive(Error, Debug)]
-pub enum Error {
-    FileNotFound(PathBuf),
-}
-
-// TODO: use thiserror, color_eyre (if compatible with pydisseqt / python) or whatever
-impl Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Error::FileNotFound(path_buf) => write!(f, "File not found: {}", path_buf.display()),
-        }
-    }
-}
-
 pub struct DsvSequence {
     rf: rf::Rf,
     gx: grad::Grad,
     gy: grad::Grad,
     gz: grad::Grad,
     adc: adc::Adc,
+    /// How the channels are sampled between their samples
+    interpolation: Interpolation,
 }
 
 impl DsvSequence {
     pub fn load<P: AsRef<Path>>(
         path: P,
         resolution: Option<usize>,
-        ref_voltage: f64,
+        ref_voltage: Option<f64>,
+        interpolation: Interpolation,
+    ) -> Result<Self, Error> {
+        Self::from_source(
+            &DsvSource::Path(path.as_ref()),
+            resolution,
+            ref_voltage,
+            interpolation,
+        )
+    }
+
+    /// Load from the contents of the DSV files, indexed by channel name
+    /// (the `XXX` in `<stem>_XXX.dsv`, e.g.: `"RFD"`, `"GRX"`, `"ADC"`)
+    pub fn from_buffers(
+        buffers: &HashMap<String, Vec<u8>>,
+        resolution: Option<usize>,
+        ref_voltage: Option<f64>,
+        interpolation: Interpolation,
+    ) -> Result<Self, Error> {
+        Self::from_source(
+            &DsvSource::Buffers(buffers),
+            resolution,
+            ref_voltage,
+            interpolation,
+        )
+    }
+
+    fn from_source(
+        source: &DsvSource,
+        resolution: Option<usize>,
+        ref_voltage: Option<f64>,
+        interpolation: Interpolation,
     ) -> Result<Self, Error> {
-        let rf = rf::Rf::load(&path, ref_voltage)?;
-        let gx = grad::Grad::load(&path, "GRX")?;
-        let gy = grad::Grad::load(&path, "GRY")?;
-        let gz = grad::Grad::load(&path, "GRZ")?;
-        let adc = adc::Adc::load(path, resolution)?;
+        let rf = rf::Rf::load(source, ref_voltage)?;
+        let gx = grad::Grad::load(source, "GRX")?;
+        let gy = grad::Grad::load(source, "GRY")?;
+        let gz = grad::Grad::load(source, "GRZ")?;
+        let adc = adc::Adc::load(source, resolution)?;
 
         Ok(Self {
             rf,
@@ -49,6 +70,7 @@ impl DsvSequence {
             gy,
             gz,
             adc,
+            interpolation,
         })
     }
 }
@@ -60,6 +82,26 @@ impl Backend for DsvSequence {
         None
     }
 
+    fn metadata(&self) -> Metadata {
+        // DSV headers don't state a total duration, see `duration()` instead
+        Metadata {
+            nominal_frequency: Some(self.rf.frequency),
+            definitions: self.rf.definitions.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn rasters(&self) -> Option<Rasters> {
+        Some(Rasters {
+            rf: self.rf.time_step,
+            gradient_x: self.gx.time_step,
+            gradient_y: self.gy.time_step,
+            gradient_z: self.gz.time_step,
+            adc: self.adc.time_step,
+            block: None,
+        })
+    }
+
     fn duration(&self) -> f64 {
         *[
             self.rf.duration(),
@@ -73,95 +115,219 @@ impl Backend for DsvSequence {
         .unwrap()
     }
 
-    fn events(&self, ty: crate::EventType, t_start: f64, t_end: f64, max_count: usize) -> Vec<f64> {
+    fn events_iter(
+        &self,
+        ty: crate::EventType,
+        t_start: f64,
+        t_end: f64,
+    ) -> Box<dyn Iterator<Item = f64> + '_> {
         match ty {
-            crate::EventType::RfPulse => self.rf.events(t_start, t_end, max_count),
-            crate::EventType::Adc => self.adc.events(t_start, t_end, max_count),
+            crate::EventType::RfPulse => Box::new(self.rf.events(t_start, t_end)),
+            crate::EventType::Adc => Box::new(self.adc.events(t_start, t_end)),
             crate::EventType::Gradient(channel) => match channel {
-                crate::GradientChannel::X => self.gx.events(t_start, t_end, max_count),
-                crate::GradientChannel::Y => self.gy.events(t_start, t_end, max_count),
-                crate::GradientChannel::Z => self.gz.events(t_start, t_end, max_count),
+                crate::GradientChannel::X => Box::new(self.gx.events(t_start, t_end)),
+                crate::GradientChannel::Y => Box::new(self.gy.events(t_start, t_end)),
+                crate::GradientChannel::Z => Box::new(self.gz.events(t_start, t_end)),
             },
+            // DSV files don't contain triggers
+            crate::EventType::Trigger => Box::new(std::iter::empty()),
         }
     }
 
-    fn encounter(&self, t_start: f64, ty: crate::EventType) -> Option<(f64, f64)> {
+    fn encounters(
+        &self,
+        ty: crate::EventType,
+        t_start: f64,
+        t_end: f64,
+    ) -> Box<dyn Iterator<Item = (f64, f64)> + '_> {
         match ty {
-            crate::EventType::RfPulse => self.rf.encounter(t_start),
-            crate::EventType::Adc => self.adc.encounter(t_start),
+            crate::EventType::RfPulse => Box::new(self.rf.encounters(t_start, t_end)),
+            crate::EventType::Adc => Box::new(self.adc.encounters(t_start, t_end)),
             crate::EventType::Gradient(channel) => match channel {
-                crate::GradientChannel::X => self.gx.encounter(t_start),
-                crate::GradientChannel::Y => self.gy.encounter(t_start),
-                crate::GradientChannel::Z => self.gz.encounter(t_start),
+                crate::GradientChannel::X => Box::new(self.gx.encounters(t_start, t_end)),
+                crate::GradientChannel::Y => Box::new(self.gy.encounters(t_start, t_end)),
+                crate::GradientChannel::Z => Box::new(self.gz.encounters(t_start, t_end)),
             },
+            crate::EventType::Trigger => Box::new(std::iter::empty()),
         }
     }
 
-    fn sample(&self, time: &[f64]) -> Vec<crate::Sample> {
-        // TODO: look if this rounding is correct / where is the center of a sample?
-
-        // TODO: maybe the current backend trait is suboptimal; It would be much
-        // nicer if we could create the Vec types here directly.
-        // Maybe provide both sample and sample_vec in the trait, with blanket impls?
-
-        time.iter()
-            .map(|&t| {
-                // very much repetition - can we unify shapes somehow?
-
-                // TODO: no out of bounds protection
-                let index = (t / self.rf.time_step).round() as usize;
-
-                let pulse = crate::RfPulseSample {
-                    amplitude: *self.rf.amplitude.get(index).unwrap_or(&0.0),
-                    phase: *self.rf.phase.get(index).unwrap_or(&0.0),
-                    frequency: self.rf.frequency,
-                    shim: None,
-                };
-
-                let gradient = crate::GradientSample {
-                    x: self.gx.sample(t),
-                    y: self.gy.sample(t),
-                    z: self.gz.sample(t),
-                };
-
-                // TODO: no out of bounds protection
-                let index = (t / self.adc.time_step).round() as usize;
-                let adc = crate::AdcBlockSample {
-                    active: *self.adc.active.get(index).unwrap_or(&false),
-                    phase: *self.adc.phase.get(index).unwrap_or(&0.0),
-                    frequency: self.adc.frequency,
-                };
-
-                crate::Sample {
-                    pulse,
-                    gradient,
-                    adc,
-                }
-            })
-            .collect()
-    }
-
-    fn integrate(&self, time: &[f64]) -> Vec<Moment> {
-        let mut moments = Vec::new();
-        for t in time.windows(2) {
-            let mut spin = util::Spin::relaxed();
-            self.rf.integrate(&mut spin, t[0], t[1]);
+    fn adc_readouts(
+        &self,
+        t_start: f64,
+        t_end: f64,
+    ) -> Box<dyn Iterator<Item = crate::AdcReadout> + '_> {
+        Box::new(self.adc.readouts(t_start, t_end))
+    }
+
+    fn adc_readout_at(&self, t: f64) -> Option<crate::AdcReadout> {
+        self.adc.readout_at(t)
+    }
+
+    fn sample(&self, time: &[f64], samples: &mut SampleVec) {
+        // Sample i of every channel lies at (i + 0.5) * time_step, see `Interpolation`
+        samples.clear();
+        let interpolation = self.interpolation;
+
+        for &t in time {
+            let rf = &self.rf;
+            let len = rf.amplitude.len().min(rf.phase.len());
+            let (amplitude, phase) = interpolation
+                .sample_polar(len, t / rf.time_step, |i| (rf.amplitude[i], rf.phase[i]))
+                .unwrap_or_default();
+            samples.pulse.push(crate::RfPulseSample {
+                amplitude,
+                phase,
+                frequency: rf.frequency,
+                shim: None,
+            });
+
+            samples.gradient.push(crate::GradientSample {
+                x: self.gx.sample(t, interpolation),
+                y: self.gy.sample(t, interpolation),
+                z: self.gz.sample(t, interpolation),
+            });
 
-            let pulse = crate::RfPulseMoment {
-                angle: spin.angle(),
-                phase: spin.phase(),
+            let adc = &self.adc;
+            let x = t / adc.time_step;
+            let len = adc.active.len().min(adc.phase.len());
+            samples.adc.push(crate::AdcBlockSample {
+                // Flags can't be interpolated: take the closer one of the two samples
+                active: interpolation
+                    .samples(x, len)
+                    .is_some_and(|(a, b, w)| adc.active[if w < 0.5 { a } else { b }]),
+                phase: interpolation
+                    .sample_polar(len, x, |i| (1.0, adc.phase[i]))
+                    .map_or(0.0, |(_, phase)| phase),
+                // Like `AdcReadout`: the carrier is no offset
+                frequency: 0.0,
+            });
+
+            samples.trigger.push(crate::TriggerSample::default());
+        }
+    }
+
+    fn integrate_rf(&self, time: &[f64], options: &RfOptions, moments: &mut RfPulseMomentVec) {
+        // The nominal frequency of DSV files is the carrier, not an offset:
+        // the phase of the RF channel already contains any modulation.
+        let off_resonance = |t: f64| {
+            let Some((x, y, z)) = options.position else {
+                return options.off_resonance;
+            };
+            options.off_resonance
+                + self.gx.sample(t, self.interpolation) * x
+                + self.gy.sample(t, self.interpolation) * y
+                + self.gz.sample(t, self.interpolation) * z
+        };
+        let off_resonance: Option<&dyn Fn(f64) -> f64> =
+            if options.off_resonance == 0.0 && options.position.is_none() {
+                None
+            } else {
+                Some(&off_resonance)
             };
-            moments.push(Moment {
-                pulse,
-                gradient: crate::GradientMoment {
-                    x: self.gx.integrate(t[0], t[1]),
-                    y: self.gy.integrate(t[0], t[1]),
-                    z: self.gz.integrate(t[0], t[1]),
-                },
+
+        moments.clear();
+        for t in time.windows(2) {
+            let mut rotation = util::Rotation::IDENTITY;
+            self.rf.integrate(&mut rotation, t[0], t[1], off_resonance);
+            moments.push(util::rf_moment(rotation));
+        }
+    }
+
+    fn integrate(&self, time: &[f64], moments: &mut MomentVec) {
+        moments.clear();
+        for t in time.windows(2) {
+            let mut rotation = util::Rotation::IDENTITY;
+            self.rf.integrate(&mut rotation, t[0], t[1], None);
+            moments.pulse.push(util::rf_moment(rotation));
+            moments.gradient.push(crate::GradientMoment {
+                x: self.gx.integrate(t[0], t[1]),
+                y: self.gy.integrate(t[0], t[1]),
+                z: self.gz.integrate(t[0], t[1]),
             });
         }
-        moments
     }
 }
 
-// TODO: replace all the unwraps with errors
+#[cfg(test)]
+mod tests {
+    use super::DsvSequence;
+    use crate::{Backend, Interpolation, SampleVec};
+    use assert2::check;
+    use std::collections::HashMap;
+
+    /// DSV file with the given samples on a 10 us raster, compressed like
+    /// the scanner does: the derivative with runs `x x n` of `n + 2` times `x`
+    fn channel(unit: &str, samples: &[i64]) -> Vec<u8> {
+        let mut deriv = Vec::new();
+        let mut last = 0;
+        for &x in samples {
+            deriv.push(x - last);
+            last = x;
+        }
+        let mut values = Vec::new();
+        let mut i = 0;
+        while i < deriv.len() {
+            let run = deriv[i..].iter().take_while(|&&x| x == deriv[i]).count();
+            if run >= 2 {
+                values.extend([deriv[i], deriv[i], run as i64 - 2]);
+            } else {
+                values.push(deriv[i]);
+            }
+            i += run;
+        }
+
+        let mut file = format!(
+            "[DEFINITIONS]\nSAMPLES={}\nHORIDELTA=10\nHORIUNITNAME=us\nVERTFACTOR=1\n\
+             VERTUNITNAME={unit}\nNOMINALFREQUENCY=123200000\n[VALUES]\n",
+            samples.len()
+        );
+        for x in values {
+            file += &format!("{x}\n");
+        }
+        file.into_bytes()
+    }
+
+    #[test]
+    fn adc_frequency() {
+        // One readout from 100 to 200 us with a phase of 90°
+        let mut adc = vec![0; 40];
+        let mut phase = vec![0; 40];
+        adc[10..20].fill(1);
+        phase[10..20].fill(90);
+        let buffers: HashMap<String, Vec<u8>> = [
+            ("RFD", channel("Volt", &[0; 40])),
+            ("GRX", channel("mT/m", &[0; 40])),
+            ("GRY", channel("mT/m", &[0; 40])),
+            ("GRZ", channel("mT/m", &[0; 40])),
+            ("ADC", channel("-", &adc)),
+            ("NC1", channel("Degree", &phase)),
+        ]
+        .into_iter()
+        .map(|(name, buf)| (name.to_owned(), buf))
+        .collect();
+        let seq = DsvSequence::from_buffers(&buffers, None, Some(500.0), Interpolation::default())
+            .unwrap();
+
+        // The carrier is no frequency offset, in both APIs
+        let readout = seq.adc_readouts(0.0, f64::INFINITY).next().unwrap();
+        check!(readout.num_samples == 10);
+        check!(readout.frequency == 0.0);
+        let time: Vec<f64> = (0..readout.num_samples)
+            .map(|i| readout.sample_time(i))
+            .collect();
+        let mut samples = SampleVec::default();
+        seq.sample(&time, &mut samples);
+        check!(samples.adc.active.iter().all(|&active| active));
+        check!(samples
+            .adc
+            .frequency
+            .iter()
+            .all(|&f| f == readout.frequency));
+        check!(samples
+            .adc
+            .phase
+            .iter()
+            .all(|&phase| (phase - readout.phase).abs() < 1e-12));
+    }
+}
@@ -1,11 +1,8 @@
-use std::path::Path;
+use std::collections::HashMap;
 
-use crate::{backend_dsv::trigger::Trigger, util};
+use crate::{backend_dsv::trigger::Trigger, util, Error};
 
-use super::{
-    helpers::{decompress_shape, DsvFile},
-    Error,
-};
+use super::helpers::DsvSource;
 
 pub struct Rf {
     /// Rf amplitude in volts
@@ -16,36 +13,50 @@ pub struct Rf {
     pub time_step: f64,
     /// Frequency in Hz
     pub frequency: f64,
+    /// Raw definitions of the RFD file
+    pub definitions: HashMap<String, String>,
     /// Location of pulses
     events: Trigger,
 }
 
 impl Rf {
-    pub fn load<P: AsRef<Path>>(path: P, ref_voltage: f64) -> Result<Self, Error> {
-        let amplitude = RfRaw::load(&path, "RFD", Some(ref_voltage))?;
+    /// `ref_voltage` is only required if the RFD file stores amplitudes in Volt
+    pub fn load(source: &DsvSource, ref_voltage: Option<f64>) -> Result<Self, Error> {
+        let amplitude = RfRaw::load(source, "RFD", ref_voltage)?;
 
         // Seems like there is not always an RFP file
-        let phase = if let Ok(phase) = RfRaw::load(&path, "RFP", None) {
-            // TODO: return errors instead of panicking
-            assert_eq!(amplitude.data.len(), phase.data.len());
-            assert_eq!(amplitude.time_step, phase.time_step);
-            assert_eq!(amplitude.frequency, phase.frequency);
-            phase.data
-        } else {
-            // Try to load the data from the ADC file
-            if let Ok(nco) = crate::backend_dsv::adc::AdcRaw::load(&path, "NC1") {
-                let step = nco.data.len() / amplitude.data.len();
-                if amplitude.data.len() * step == nco.data.len() && step <= 10 {
-                    nco.data.into_iter().step_by(step).collect()
-                } else {
-                    vec![0.0; amplitude.data.len()]
+        let phase = match RfRaw::load(source, "RFP", None) {
+            Ok(phase) => {
+                if amplitude.data.len() != phase.data.len() {
+                    return Err(amplitude.mismatch(&phase, "sample count"));
+                }
+                if amplitude.time_step != phase.time_step {
+                    return Err(amplitude.mismatch(&phase, "time step"));
+                }
+                if amplitude.frequency != phase.frequency {
+                    return Err(amplitude.mismatch(&phase, "frequency"));
+                }
+                phase.data
+            }
+            Err(Error::FileNotFound(_) | Error::MissingChannel(_)) => {
+                // Try to load the data from the ADC file
+                let len = amplitude.data.len();
+                match crate::backend_dsv::adc::AdcRaw::load(source, "NC1") {
+                    Ok(nco) if len > 0 && nco.data.len() % len == 0 => {
+                        let step = nco.data.len() / len;
+                        if (1..=10).contains(&step) {
+                            nco.data.into_iter().step_by(step).collect()
+                        } else {
+                            vec![0.0; len]
+                        }
+                    }
+                    _ => vec![0.0; len],
                 }
-            } else {
-                vec![0.0; amplitude.data.len()]
             }
+            Err(err) => return Err(err),
         };
 
-        let events = Trigger::new(&amplitude.data);
+        let events = Trigger::new(&amplitude.data, &amplitude.name)?;
         // println!("{events:?}");
 
         Ok(Self {
@@ -53,6 +64,7 @@ impl Rf {
             phase,
             time_step: amplitude.time_step,
             frequency: amplitude.frequency,
+            definitions: amplitude.definitions,
             events,
         })
     }
@@ -61,30 +73,40 @@ impl Rf {
         self.time_step * self.amplitude.len() as f64
     }
 
-    pub fn events(&self, t_start: f64, t_end: f64, max_count: usize) -> Vec<f64> {
+    pub fn events(&self, t_start: f64, t_end: f64) -> impl Iterator<Item = f64> + '_ {
         // Simple solution: we are on a fixed raster - return that.
         // Could only return events within encounters, but we assume that
         // The user checks where those encounters are themselves.
         let i_start = (t_start / self.time_step).ceil() as usize;
         let i_end = (t_end / self.time_step).ceil() as usize;
 
-        (i_start..i_end)
-            .take(max_count)
-            .map(|i| i as f64 * self.time_step)
-            .collect()
+        (i_start..i_end).map(|i| i as f64 * self.time_step)
     }
 
-    pub fn encounter(&self, t_start: f64) -> Option<(f64, f64)> {
+    pub fn encounters(&self, t_start: f64, t_end: f64) -> impl Iterator<Item = (f64, f64)> + '_ {
         let i_start = (t_start / self.time_step).ceil() as usize;
-        let (i_start, i_end) = self.events.search(i_start)?;
 
-        Some((
-            i_start as f64 * self.time_step,
-            (i_end + 1) as f64 * self.time_step,
-        ))
+        self.events
+            .search(i_start)
+            .map(|(i_start, i_end)| {
+                (
+                    i_start as f64 * self.time_step,
+                    (i_end + 1) as f64 * self.time_step,
+                )
+            })
+            .take_while(move |&(start, _)| start < t_end)
     }
 
-    pub fn integrate(&self, spin: &mut util::Spin, t_start: f64, t_end: f64) {
+    /// Applies the part of the RF channel inside of [t_start, t_end] to `rotation`.
+    /// `off_resonance` returns the off-resonance in Hz at the given time,
+    /// without it the channel is integrated on-resonant.
+    pub fn integrate(
+        &self,
+        rotation: &mut util::Rotation,
+        t_start: f64,
+        t_end: f64,
+        off_resonance: Option<&dyn Fn(f64) -> f64>,
+    ) {
         // TODO: this is not performant for integrations over long time periods
         // because it will sum up all zeros of the empty space between pulses
         let i_start = (t_start / self.time_step).floor() as usize;
@@ -103,19 +125,25 @@ impl Rf {
             // We could do the clamping for all samples, but when integrating
             // over many samples, it seems to be very sensitive to accumulating
             // errors. Only doing it in the edge cases is much more robust.
-            let dur = if t_start <= t && t + self.time_step <= t_end {
-                self.time_step
+            let (dur, t_mid) = if t_start <= t && t + self.time_step <= t_end {
+                (self.time_step, t + 0.5 * self.time_step)
             } else {
                 // Clamp the sample intervall to the integration intervall
                 let t0 = t.clamp(t_start, t_end);
                 let t1 = (t + self.time_step).clamp(t_start, t_end);
-                t1 - t0
+                (t1 - t0, 0.5 * (t0 + t1))
             };
 
-            *spin *= util::Rotation::new(
-                self.amplitude[i] * dur * std::f64::consts::TAU,
-                self.phase[i],
-            );
+            let amp = self.amplitude[i] * std::f64::consts::TAU;
+            let phase = self.phase[i];
+            *rotation = match off_resonance {
+                None => util::Rotation::new(amp * dur, phase),
+                Some(off_resonance) => {
+                    let dw = off_resonance(t_mid) * std::f64::consts::TAU;
+                    let axis = [amp * phase.cos(), amp * phase.sin(), dw];
+                    util::Rotation::from_axis_angle(axis, amp.hypot(dw) * dur)
+                }
+            } * *rotation;
         }
     }
 }
@@ -125,23 +153,23 @@ struct RfRaw {
     data: Vec<f64>,
     time_step: f64,
     frequency: f64,
+    definitions: HashMap<String, String>,
+    name: String,
 }
 impl RfRaw {
-    pub fn load<P: AsRef<Path>>(
-        path: P,
+    pub fn load(
+        source: &DsvSource,
         which_dsv: &str,
         ref_voltage: Option<f64>,
     ) -> Result<Self, Error> {
-        let dsv = DsvFile::load(&path, which_dsv)?;
+        let dsv = source.load(which_dsv)?;
 
-        // TODO: don't unwrap but return the parse errors
-        // TODO: do the same with key errors (currently panics)
-        let num_samples: usize = dsv.definitions["SAMPLES"].parse().unwrap();
-        let time_step = dsv.time_step();
-        let amp_step = dsv.amp_step(ref_voltage);
-        let frequency = dsv.definitions["NOMINALFREQUENCY"].parse::<f64>().unwrap();
+        let time_step = dsv.time_step()?;
+        let amp_step = dsv.amp_step(ref_voltage)?;
+        let frequency = dsv.parse_def("NOMINALFREQUENCY")?;
 
-        let data: Vec<f64> = decompress_shape(dsv.values, num_samples)
+        let data: Vec<f64> = dsv
+            .decompress()?
             .into_iter()
             .map(|x| x as f64 * amp_step)
             .collect();
@@ -150,6 +178,16 @@ impl RfRaw {
             data,
             time_step,
             frequency,
+            definitions: dsv.definitions,
+            name: dsv.name,
         })
     }
+
+    fn mismatch(&self, other: &Self, property: &'static str) -> Error {
+        Error::ChannelMismatch {
+            file_a: self.name.clone(),
+            file_b: other.name.clone(),
+            property,
+        }
+    }
 }
@@ -1,3 +1,5 @@
+use crate::Error;
+
 #[derive(Debug)]
 pub struct Trigger {
     /// The indices of the first and last value of an event that are not zero.
@@ -7,15 +9,21 @@ pub struct Trigger {
 }
 
 impl Trigger {
-    pub fn new(samples: &[f64]) -> Self {
+    /// `file` is only used for error messages
+    pub fn new(samples: &[f64], file: &str) -> Result<Self, Error> {
         let mut starts = Vec::new();
         let mut ends = Vec::new();
 
         // Trigger window size - amount of zeros that separate pulses
         const WND: usize = 10;
         let n_samples = samples.len();
-        // TODO: return error or empty Trigger?
-        assert!(n_samples > WND);
+        if n_samples <= WND {
+            return Err(Error::TooFewSamples {
+                file: file.to_owned(),
+                count: n_samples,
+                min: WND,
+            });
+        }
 
         // There might be less zeros before the first start
         if let Some(i) = samples.iter().take(WND - 1).position(|&x| x != 0.0) {
@@ -37,37 +45,54 @@ impl Trigger {
             ends.push((n_samples - i).min(n_samples - 1));
         }
 
-        // Logic bug in this code if any of this triggers
-        assert_eq!(starts.len(), ends.len());
+        // Isolated samples at the very start or end of the channel can't be
+        // split into events by the logic above - report instead of panicking
+        let invalid = || Error::InvalidEvents {
+            file: file.to_owned(),
+        };
+        if starts.len() != ends.len() {
+            return Err(invalid());
+        }
+
+        let events: Vec<(usize, usize)> = starts.into_iter().zip(ends).collect();
+
+        // Check if sorted and no overlap
+        if !events.iter().all(|&(start, end)| start < end)
+            || !events.windows(2).all(|w| w[0].1 < w[1].0)
+        {
+            return Err(invalid());
+        }
+
+        Ok(Self { events })
+    }
 
-        let events: Vec<(usize, usize)> = starts
-            .into_iter()
-            .zip(ends)
-            .map(|(start, end)| {
-                assert!(start < end);
-                (start, end)
-            })
-            .collect();
+    /// All events starting at or after `i_start`
+    pub fn search(&self, i_start: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.events[self.search_position(i_start)..].iter().copied()
+    }
 
-        // Check if sorted and no overlap - w[x].0 < w[x].1 is guaranteed above
-        assert!(events.windows(2).all(|w| w[0].1 < w[1].0));
+    /// Number of events starting before `i_start`
+    pub fn search_position(&self, i_start: usize) -> usize {
+        self.events.partition_point(|&(start, _)| start < i_start)
+    }
 
-        Self { events }
+    /// The event with the given index, counted from the first one
+    pub fn get(&self, index: usize) -> Option<(usize, usize)> {
+        self.events.get(index).copied()
     }
 
-    pub fn search(&self, i_start: usize) -> Option<(usize, usize)> {
+    /// Index of the first event that ends after or starts at `i_start`
+    pub fn overlap_position(&self, i_start: usize) -> usize {
         match self
             .events
             .binary_search_by_key(&i_start, |&(start, _)| start)
         {
-            // we are exactly on the starting point of the event
-            Ok(idx) => Some(self.events[idx]),
-            // we must check if we are before the event
+            Ok(idx) => idx,
             Err(idx) => {
-                if idx < self.events.len() && i_start < self.events[idx].0 {
-                    Some(self.events[idx])
+                if idx > 0 && self.events[idx - 1].1 > i_start {
+                    idx - 1
                 } else {
-                    None
+                    idx
                 }
             }
         }
@@ -79,19 +104,7 @@ impl Trigger {
         i_end: usize,
     ) -> impl Iterator<Item = (usize, usize)> + '_ {
         // Index of the first event overlapping with the time range
-        let idx = match self
-            .events
-            .binary_search_by_key(&i_start, |&(start, _)| start)
-        {
-            Ok(idx) => idx,
-            Err(idx) => {
-                if idx > 0 && self.events[idx - 1].1 > i_start {
-                    idx - 1
-                } else {
-                    idx
-                }
-            }
-        };
+        let idx = self.overlap_position(i_start);
 
         // TODO: is this correct? evt_start and evt_end values are inclusive currently!
 
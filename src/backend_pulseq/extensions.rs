@@ -0,0 +1,352 @@
+//! pulseq-rs ignores the EXT column of `[BLOCKS]` and the `[EXTENSIONS]`
+//! section, so extensions are parsed from the sections split by `raw.rs`.
+
+use std::collections::HashMap;
+
+use super::raw::RawSeq;
+use crate::{Error, Labels, Rotation};
+
+/// All extensions of a .seq file, still in their raw (string) form.
+#[derive(Debug, Default)]
+pub struct Extensions {
+    /// Id of the first entry of the extension list of every block, by block id
+    block_ext: HashMap<u32, u32>,
+    /// Linked list nodes of `[EXTENSIONS]`: id -> (type, reference, next id)
+    list: HashMap<u32, (u32, u32, u32)>,
+    /// Extension type id -> name, from the `extension NAME id` headers
+    names: HashMap<u32, String>,
+    /// Rows of all extension tables without their id, by (type, id)
+    tables: HashMap<(u32, u32), Vec<String>>,
+}
+
+/// A single entry of the extension list of a block
+#[derive(Debug)]
+pub struct Extension<'a> {
+    /// Name of the extension type, e.g.: `"LABELSET"` or `"TRIGGERS"`
+    pub name: &'a str,
+    /// Id of the row in the extension table
+    pub id: u32,
+    /// The row of the extension table, without the id
+    pub data: &'a [String],
+}
+
+/// A trigger of the TRIGGERS extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockTrigger {
+    /// Input triggers wait for e.g. an ECG signal, others are output triggers
+    pub input: bool,
+    pub channel: u32,
+    /// Delay relative to the block start in seconds
+    pub delay: f64,
+    /// Duration in seconds
+    pub duration: f64,
+}
+
+/// A soft delay of the DELAYS extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockSoftDelay<'a> {
+    pub num: u32,
+    /// Offset in seconds
+    pub offset: f64,
+    pub factor: f64,
+    pub hint: &'a str,
+}
+
+impl Extensions {
+    pub fn parse(raw: &RawSeq) -> Result<Self, Error> {
+        let mut ext = Self {
+            block_ext: raw
+                .blocks
+                .iter()
+                .filter(|block| block.ext != 0)
+                .map(|block| (block.id, block.ext))
+                .collect(),
+            ..Default::default()
+        };
+
+        // The extension list is followed by the tables, each with a header
+        let mut table = None;
+        for &(line, content) in raw.section("EXTENSIONS") {
+            let invalid = || Error::InvalidExtension {
+                line,
+                content: content.to_owned(),
+            };
+
+            if let Some(header) = content.strip_prefix("extension ") {
+                // Header of an extension table: `extension NAME type_id`
+                let mut fields = header.split_whitespace();
+                let (Some(name), Some(ty), None) = (fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(invalid());
+                };
+                let ty = ty.parse().map_err(|_| invalid())?;
+                ext.names.insert(ty, name.to_owned());
+                table = Some(ty);
+                continue;
+            }
+
+            let mut fields = content.split_whitespace();
+            match table {
+                None => {
+                    // id type ref next_id
+                    let values: Vec<u32> = fields
+                        .map(|x| x.parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| invalid())?;
+                    let &[id, ty, reference, next] = values.as_slice() else {
+                        return Err(invalid());
+                    };
+                    ext.list.insert(id, (ty, reference, next));
+                }
+                Some(ty) => {
+                    let id = fields.next().and_then(|id| id.parse().ok());
+                    let id = id.ok_or_else(invalid)?;
+                    ext.tables
+                        .insert((ty, id), fields.map(|x| x.to_owned()).collect());
+                }
+            }
+        }
+
+        Ok(ext)
+    }
+
+    /// Iterates over the extension list of the given block in file order.
+    /// Entries that reference missing types or table rows are skipped.
+    pub fn block(&self, block_id: u32) -> impl Iterator<Item = Extension<'_>> {
+        let mut next = self.block_ext.get(&block_id).copied().unwrap_or(0);
+
+        // Every list entry can only be visited once, this protects from cycles
+        std::iter::from_fn(move || {
+            let &(ty, reference, n) = self.list.get(&next)?;
+            next = n;
+            Some((ty, reference))
+        })
+        .take(self.list.len())
+        .filter_map(|(ty, id)| {
+            Some(Extension {
+                name: self.names.get(&ty)?,
+                id,
+                data: self.tables.get(&(ty, id))?,
+            })
+        })
+    }
+
+    /// Returns all triggers of the given block in file order. TRIGGERS rows
+    /// have the format `type channel delay duration`, with type 1 = output,
+    /// 2 = input and times in microseconds. Malformed rows are ignored.
+    pub fn triggers(&self, block_id: u32) -> Vec<BlockTrigger> {
+        self.block(block_id)
+            .filter(|ext| ext.name == "TRIGGERS")
+            .filter_map(|ext| {
+                let [ty, channel, delay, duration] = ext.data else {
+                    return None;
+                };
+                Some(BlockTrigger {
+                    input: ty.parse::<u32>().ok()? == 2,
+                    channel: channel.parse().ok()?,
+                    delay: delay.parse::<f64>().ok()? * 1e-6,
+                    duration: duration.parse::<f64>().ok()? * 1e-6,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the soft delay of the given block. DELAYS rows have the format
+    /// `num offset factor hint`, with the offset in microseconds. Malformed
+    /// rows are ignored.
+    pub fn soft_delay(&self, block_id: u32) -> Option<BlockSoftDelay<'_>> {
+        self.block(block_id)
+            .filter(|ext| ext.name == "DELAYS")
+            .find_map(|ext| {
+                let [num, offset, factor, hint] = ext.data else {
+                    return None;
+                };
+                Some(BlockSoftDelay {
+                    num: num.parse().ok()?,
+                    offset: offset.parse::<f64>().ok()? * 1e-6,
+                    factor: factor.parse().ok()?,
+                    hint,
+                })
+            })
+    }
+
+    /// Returns the gradient rotation of the given block as matrix, which turns
+    /// logical into physical gradients. ROTATIONS rows either contain a
+    /// quaternion `w x y z` (as written by pulseq 1.5) or a row-major 3x3
+    /// rotation matrix. Malformed rows are ignored.
+    pub fn rotation(&self, block_id: u32) -> Option<[[f64; 3]; 3]> {
+        self.block(block_id)
+            .filter(|ext| ext.name == "ROTATIONS")
+            .find_map(|ext| {
+                let values: Vec<f64> = ext
+                    .data
+                    .iter()
+                    .map(|x| x.parse().ok())
+                    .collect::<Option<_>>()?;
+                match values[..] {
+                    [w, x, y, z] => {
+                        let rot = Rotation { w, x, y, z }.normalized();
+                        // The columns of the matrix are the rotated basis vectors
+                        let cols = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+                            .map(|e| rot.apply(e));
+                        Some([0, 1, 2].map(|i| [cols[0][i], cols[1][i], cols[2][i]]))
+                    }
+                    [a, b, c, d, e, f, g, h, i] => Some([[a, b, c], [d, e, f], [g, h, i]]),
+                    _ => None,
+                }
+            })
+    }
+
+    /// Evaluates the LABELSET and LABELINC extensions over the given blocks.
+    /// Returns the label state of every block that changes it, together with
+    /// the index of the block in `block_ids`. The state includes the extensions
+    /// of the block itself - so it is the state seen by the ADC of the block.
+    /// Blocks without an entry keep the state of the previous one.
+    pub fn labels(&self, block_ids: impl Iterator<Item = u32>) -> Vec<(usize, Labels)> {
+        let mut labels = Labels::default();
+
+        block_ids
+            .enumerate()
+            .filter_map(|(i, id)| {
+                let before = labels.clone();
+                for ext in self.block(id) {
+                    let [value, label] = ext.data else {
+                        continue;
+                    };
+                    let Ok(value) = value.parse::<i64>() else {
+                        continue;
+                    };
+                    match ext.name {
+                        "LABELSET" => labels.set(label, value),
+                        "LABELINC" => labels.set(label, labels.get(label) + value),
+                        _ => (),
+                    }
+                }
+                (labels != before).then(|| (i, labels.clone()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockTrigger, Extensions, RawSeq};
+    use assert2::check;
+
+    fn parse(source: &str) -> Result<Extensions, crate::Error> {
+        Extensions::parse(&RawSeq::parse(source)?)
+    }
+
+    const SOURCE: &str = "\
+[BLOCKS]
+# NUM DUR RF  GX  GY  GZ  ADC  EXT
+1 100 1 0 0 0 0 1
+2 100 0 0 0 0 1 3
+3 100 0 0 0 0 1 3
+4 100 0 0 0 0 0 0
+
+[EXTENSIONS]
+1 1 1 2
+2 2 1 0
+3 1 2 0
+
+extension LABELINC 1
+1 1 LIN
+2 1 LIN
+
+extension LABELSET 2
+1 4 SLC
+";
+
+    #[test]
+    fn block_lists() {
+        let ext = parse(SOURCE).unwrap();
+        let names: Vec<_> = ext.block(1).map(|e| e.name).collect();
+        check!(names == ["LABELINC", "LABELSET"]);
+        check!(ext.block(4).count() == 0);
+        check!(ext.block(2).next().unwrap().data == ["1", "LIN"]);
+    }
+
+    #[test]
+    fn labels() {
+        let ext = parse(SOURCE).unwrap();
+        let labels = ext.labels(1..=4);
+        // Block 4 has no extensions and keeps the state of block 3
+        let indices: Vec<_> = labels.iter().map(|(i, _)| *i).collect();
+        check!(indices == [0, 1, 2]);
+        let lin: Vec<_> = labels.iter().map(|(_, l)| l.get("LIN")).collect();
+        check!(lin == [1, 2, 3]);
+        check!(labels[2].1.get("SLC") == 4);
+        check!(labels[2].1.get("PAR") == 0);
+    }
+
+    #[test]
+    fn triggers() {
+        let source = "\
+[BLOCKS]
+1 100 0 0 0 0 0 1
+2 100 0 0 0 0 0 0
+
+[EXTENSIONS]
+1 1 1 2
+2 1 2 0
+
+extension TRIGGERS 1
+1 2 1 0 2000
+2 1 3 2000 2000
+";
+        let ext = parse(source).unwrap();
+        check!(
+            ext.triggers(1)
+                == [
+                    BlockTrigger {
+                        input: true,
+                        channel: 1,
+                        delay: 0.0,
+                        duration: 2e-3
+                    },
+                    BlockTrigger {
+                        input: false,
+                        channel: 3,
+                        delay: 2e-3,
+                        duration: 2e-3
+                    }
+                ]
+        );
+        check!(ext.triggers(2).is_empty());
+    }
+
+    #[test]
+    fn rotations() {
+        let source = "\
+[BLOCKS]
+1 100 0 0 0 0 0 1
+2 100 0 0 0 0 0 2
+
+[EXTENSIONS]
+1 1 1 0
+2 1 2 0
+
+extension ROTATIONS 1
+1 0.70710678118654752 0 0 0.70710678118654752
+2 0 -1 0 1 0 0 0 0 1
+";
+        let ext = parse(source).unwrap();
+        // Both are a rotation by 90° around z: x -> y
+        for block in [1, 2] {
+            let rot = ext.rotation(block).unwrap();
+            let expected = [[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+            for (row, expected) in rot.iter().zip(expected) {
+                for (x, expected) in row.iter().zip(expected) {
+                    check!((x - expected).abs() < 1e-12);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        check!(let Err(crate::Error::InvalidExtension { line: 3, .. }) =
+            parse("[EXTENSIONS]\n1 1 1 0\n1 1 x 0\n"));
+    }
+}
@@ -1,23 +1,104 @@
-use pulseq_rs::{Gradient, Rf, Shape};
+use std::sync::Arc;
 
-use crate::util::{Rotation, Spin};
+use pulseq_rs::{Gradient, Rf};
+
+use crate::{
+    util::{self, Rotation},
+    Interpolation,
+};
+
+/// A gradient of a block. pulseq-rs assumes that all free gradients are
+/// sampled on the gradient raster, so free gradients with a time shape (like
+/// the extended trapezoids of pypulseq) are parsed from the source instead.
+#[derive(Clone)]
+pub enum Grad {
+    Pulseq {
+        grad: Arc<Gradient>,
+        /// Start and end of free gradients relative to their amplitude, either
+        /// stored in the file (pulseq 1.5) or extrapolated from the shape
+        edges: (f64, f64),
+    },
+    Shaped(Arc<ShapedGrad>),
+}
+
+/// Free gradient with a time shape, piecewise linear between its vertices
+#[derive(Debug, PartialEq)]
+pub struct ShapedGrad {
+    pub delay: f64,
+    /// Non-decreasing vertex times relative to the end of the delay
+    pub times: Vec<f64>,
+    /// Amplitudes at the vertices in Hz/m
+    pub amps: Vec<f64>,
+}
+
+impl Grad {
+    /// `first_last` are the amplitudes in Hz/m stored by pulseq 1.5
+    pub fn pulseq(grad: Arc<Gradient>, first_last: Option<(f64, f64)>) -> Self {
+        let edges = match (grad.as_ref(), first_last) {
+            (Gradient::Free { amp, .. }, Some((first, last))) if *amp != 0.0 => {
+                (first / amp, last / amp)
+            }
+            (Gradient::Free { shape, .. }, _) => util::extrapolated_edges(&shape.0),
+            // Trapezoids ramp up from and down to zero
+            (Gradient::Trap { .. }, _) => (0.0, 0.0),
+        };
+        Grad::Pulseq { grad, edges }
+    }
+
+    pub fn delay(&self) -> f64 {
+        match self {
+            Grad::Pulseq { grad, .. } => grad.delay(),
+            Grad::Shaped(grad) => grad.delay,
+        }
+    }
+
+    /// End of the gradient relative to the block start, including the delay
+    pub fn duration(&self, grad_raster: f64) -> f64 {
+        match self {
+            Grad::Pulseq { grad, .. } => grad.duration(grad_raster),
+            Grad::Shaped(grad) => grad.delay + grad.times.last().copied().unwrap_or(0.0),
+        }
+    }
+
+    /// Largest absolute amplitude in Hz/m
+    pub fn max_amp(&self) -> f64 {
+        match self {
+            Grad::Pulseq { grad, .. } => match grad.as_ref() {
+                Gradient::Free { amp, shape, .. } => {
+                    amp.abs() * shape.0.iter().fold(0.0, |max: f64, x| max.max(x.abs()))
+                }
+                Gradient::Trap { amp, .. } => amp.abs(),
+            },
+            Grad::Shaped(grad) => grad.amps.iter().fold(0.0, |max, x| max.max(x.abs())),
+        }
+    }
+}
 
 pub fn integrate_grad(
-    gx: &Gradient,
+    grad: &Grad,
     t_start: f64,
     t_end: f64,
     block_start: f64,
     grad_raster: f64,
 ) -> f64 {
-    match gx {
-        Gradient::Free { amp, delay, shape } => {
-            amp * integrate_free(
-                t_start - block_start - delay,
-                t_end - block_start - delay,
-                shape,
-                grad_raster,
+    let (grad, edges) = match grad {
+        Grad::Pulseq { grad, edges } => (grad.as_ref(), *edges),
+        Grad::Shaped(grad) => {
+            return integrate_shaped(
+                t_start - block_start - grad.delay,
+                t_end - block_start - grad.delay,
+                grad,
             )
         }
+    };
+    match grad {
+        Gradient::Free { amp, delay, shape } => {
+            // Pulseq defines free gradients at the raster centers, with
+            // linear interpolation in between and to the edges
+            let to_raster = |t: f64| (t - block_start - delay) / grad_raster;
+            amp * grad_raster
+                * util::integrate_grad_shape(&shape.0, edges, to_raster(t_start), to_raster(t_end))
+        }
         Gradient::Trap {
             amp,
             rise,
@@ -36,15 +117,24 @@ pub fn integrate_grad(
     }
 }
 
-// TODO: change spin + rotation matrix to a unified rotation struct (matrix or quaternion etc.)
-// that is returned from this function
+/// Rotating frame for off-resonant RF integration, see `RfOptions`
+pub struct RfFrame<'a> {
+    /// Modulate the phase of the pulse with its frequency offset
+    pub apply_freq_offset: bool,
+    /// Off-resonance of the spin in Hz at the given (absolute) time
+    pub off_resonance: &'a dyn Fn(f64) -> f64,
+}
+
+/// Applies the part of the pulse inside of [t_start, t_end] to `rotation`.
+/// Without a frame, the pulse is integrated on-resonant.
 pub fn integrate_rf(
     rf: &Rf,
-    spin: &mut Spin,
+    rotation: &mut Rotation,
     t_start: f64,
     t_end: f64,
     block_start: f64,
     rf_raster: f64,
+    frame: Option<&RfFrame>,
 ) {
     for i in 0..rf.amp_shape.0.len() {
         let dwell = rf_raster;
@@ -62,27 +152,44 @@ pub fn integrate_rf(
         // We could do the clamping for all samples, but when integrating
         // over many samples, it seems to be very sensitive to accumulating
         // errors. Only doing it in the edge cases is much more robust.
-        let dur = if t_start <= t && t + dwell <= t_end {
-            dwell
+        let (dur, t_mid) = if t_start <= t && t + dwell <= t_end {
+            (dwell, t + 0.5 * dwell)
         } else {
             // Clamp the sample intervall to the integration intervall
             let t0 = t.clamp(t_start, t_end);
             let t1 = (t + dwell).clamp(t_start, t_end);
-            t1 - t0
+            (t1 - t0, 0.5 * (t0 + t1))
         };
 
-        *spin *= Rotation::new(
-            rf.amp * rf.amp_shape.0[i] * dur * std::f64::consts::TAU,
-            rf.phase + rf.phase_shape.0[i] * std::f64::consts::TAU,
-        );
+        let amp = rf.amp * rf.amp_shape.0[i] * std::f64::consts::TAU;
+        let phase = rf.phase + rf.phase_shape.0[i] * std::f64::consts::TAU;
+
+        *rotation = match frame {
+            None => Rotation::new(amp * dur, phase),
+            Some(frame) => {
+                let phase = if frame.apply_freq_offset {
+                    let t_pulse = t_mid - block_start - rf.delay;
+                    phase + rf.freq * t_pulse * std::f64::consts::TAU
+                } else {
+                    phase
+                };
+                let dw = (frame.off_resonance)(t_mid) * std::f64::consts::TAU;
+                let axis = [amp * phase.cos(), amp * phase.sin(), dw];
+                Rotation::from_axis_angle(axis, amp.hypot(dw) * dur)
+            }
+        } * *rotation;
     }
 }
 
-pub fn sample_grad(t: f64, grad: &Gradient, grad_raster: f64) -> f64 {
+pub fn sample_grad(t: f64, grad: &Grad, grad_raster: f64, interpolation: Interpolation) -> f64 {
+    let grad = match grad {
+        Grad::Pulseq { grad, .. } => grad.as_ref(),
+        // The exact shape is known, nothing to interpolate
+        Grad::Shaped(grad) => return sample_shaped(t - grad.delay, grad),
+    };
     match grad {
         pulseq_rs::Gradient::Free { amp, delay, shape } => {
-            let index = ((t - delay) / grad_raster - 0.5).ceil() as usize;
-            shape.0.get(index).map_or(0.0, |x| amp * x)
+            amp * interpolation.sample(&shape.0, (t - delay) / grad_raster)
         }
         pulseq_rs::Gradient::Trap {
             amp,
@@ -124,35 +231,41 @@ pub fn integrate_trap(t_start: f64, t_end: f64, rise: f64, flat: f64, fall: f64)
     integral(t_end.clamp(t_min, t_max)) - integral(t_start.clamp(t_min, t_max))
 }
 
-pub fn integrate_free(t_start: f64, t_end: f64, shape: &Shape, dwell: f64) -> f64 {
-    let mut integrated = 0.0;
-
-    for i in 0..shape.0.len() {
-        // Start time of the sample number i
-        let t = i as f64 * dwell;
+/// Amplitude of the piecewise linear gradient at time `t` after its delay
+pub fn sample_shaped(t: f64, grad: &ShapedGrad) -> f64 {
+    // Index of the first vertex after t
+    let i = grad.times.partition_point(|&x| x <= t);
+    if i == 0 || i == grad.times.len() {
+        return 0.0;
+    }
+    sample_segment(grad, i, t)
+}
 
-        // Skip samples before t_start, quit when reaching t_end
-        if t + dwell <= t_start {
-            continue;
-        }
-        if t_end <= t {
-            break;
+/// Exact integral of the piecewise linear gradient from `t_start` to `t_end`,
+/// both relative to the end of its delay
+pub fn integrate_shaped(t_start: f64, t_end: f64, grad: &ShapedGrad) -> f64 {
+    // Integral from the first vertex to t
+    let integral = |t: f64| {
+        let mut integrated = 0.0;
+        for i in 1..grad.times.len() {
+            let (t0, t1) = (grad.times[i - 1], grad.times[i]);
+            if t <= t0 {
+                break;
+            }
+            let end = t.min(t1);
+            integrated += 0.5 * (grad.amps[i - 1] + sample_segment(grad, i, end)) * (end - t0);
         }
+        integrated
+    };
+    integral(t_end) - integral(t_start)
+}
 
-        // We could do the clamping for all samples, but when integrating
-        // over many samples, it seems to be very sensitive to accumulating
-        // errors. Only doing it in the edge cases is much more robust.
-        let dur = if t_start <= t && t + dwell <= t_end {
-            dwell
-        } else {
-            // Clamp the sample intervall to the integration intervall
-            let t0 = t.clamp(t_start, t_end);
-            let t1 = (t + dwell).clamp(t_start, t_end);
-            t1 - t0
-        };
-
-        integrated += shape.0[i] * dur;
+/// Amplitude at time `t` on the segment between vertex `i - 1` and `i`
+fn sample_segment(grad: &ShapedGrad, i: usize, t: f64) -> f64 {
+    let (t0, t1) = (grad.times[i - 1], grad.times[i]);
+    if t >= t1 {
+        return grad.amps[i];
     }
-
-    integrated
+    let w = (t - t0) / (t1 - t0);
+    grad.amps[i - 1] + w * (grad.amps[i] - grad.amps[i - 1])
 }
@@ -1,25 +1,91 @@
-use std::path::Path;
+use std::{collections::HashMap, path::Path, sync::Arc};
 
-use crate::{types::*, util, Backend};
+use crate::{types::*, util, Backend, Error, LoadOptions};
 use pulseq_rs::Gradient;
 
+mod extensions;
 mod helpers;
+mod raw;
+mod validate;
+
+use extensions::{BlockTrigger, Extensions};
+use helpers::{Grad, ShapedGrad};
+use raw::{GradKey, RawSeq, RfInfo};
 
 pub struct PulseqSequence {
     // elements contain block start time
     pub blocks: Vec<(f64, pulseq_rs::Block)>,
     pub raster: pulseq_rs::TimeRaster,
     pub fov: Option<(f64, f64, f64)>,
+    pub fov_assumed_mm: bool,
+    pub definitions: HashMap<String, String>,
+    /// RF shim of every block, built once so that sampling doesn't allocate
+    shims: Vec<Option<Shim>>,
+    /// Label state after every block that changes it, by block index. Blocks
+    /// in between keep the state of the previous entry.
+    labels: Vec<(usize, Labels)>,
+    /// Trigger of every block
+    triggers: Vec<Vec<BlockTrigger>>,
+    /// Extensions of the blocks, which are not parsed by pulseq-rs
+    extensions: Extensions,
+    /// Soft delays of the DELAYS extension, ordered by their id
+    soft_delays: Vec<SoftDelay>,
+    /// Summed block durations as written in the file, before soft delays
+    file_duration: f64,
+    /// Pulseq 1.5 RF usage and center by block id
+    rf_info: HashMap<u32, RfInfo>,
+    /// Logical gradients (x, y, z) of every block. Free gradients with a time
+    /// shape are not supported by pulseq-rs and parsed from the source instead.
+    grads: Vec<[Option<Grad>; 3]>,
+    /// Gradient rotation of every block from the ROTATIONS extension
+    rotations: Vec<Option<[[f64; 3]; 3]>>,
+    /// Gradient moment from t = 0 to the start of every block, plus one
+    /// additional entry for the end of the sequence
+    grad_moments: Vec<GradientMoment>,
+    /// Indices of all blocks that contain an RF pulse
+    rf_blocks: Vec<usize>,
+    /// Indices of all blocks that contain an ADC
+    adc_blocks: Vec<usize>,
+    /// How RF and free gradient shapes are sampled
+    interpolation: Interpolation,
 }
 
 impl PulseqSequence {
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, pulseq_rs::Error> {
-        let seq = pulseq_rs::Sequence::from_file(path)?;
-        Ok(Self::from_seq(seq))
+    /// Load a .seq file. Uses the soft delays and interpolation of `options`.
+    pub fn load<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Result<Self, Error> {
+        let source = std::fs::read_to_string(path.as_ref())
+            .map_err(|_| Error::FileNotFound(path.as_ref().to_owned()))?;
+        Self::from_source_with_options(&source, options)
+    }
+
+    /// Parse the contents of a .seq file
+    pub fn from_source(source: &str) -> Result<Self, Error> {
+        Self::from_source_with_options(source, &LoadOptions::default())
+    }
+
+    /// Parse the contents of a .seq file, see [`Self::load`] for `options`
+    pub fn from_source_with_options(source: &str, options: &LoadOptions) -> Result<Self, Error> {
+        let raw = RawSeq::parse(source)?;
+        let seq = pulseq_rs::Sequence::from_source(&raw.without_time_shapes())?;
+        let extensions = Extensions::parse(&raw)?;
+        let rf_info = raw::rf_info(&raw)?;
+        let shaped_grads = raw::shaped_grads(&raw, seq.time_raster.grad)?;
+        let first_last = raw::grad_first_last(&raw)?;
+        Self::from_seq(seq, extensions, rf_info, shaped_grads, first_last, options)
     }
 
-    fn from_seq(seq: pulseq_rs::Sequence) -> Self {
-        let blocks = seq
+    fn from_seq(
+        mut seq: pulseq_rs::Sequence,
+        extensions: Extensions,
+        rf_info: HashMap<u32, RfInfo>,
+        shaped_grads: HashMap<GradKey, Arc<ShapedGrad>>,
+        first_last: HashMap<GradKey, (f64, f64)>,
+        options: &LoadOptions,
+    ) -> Result<Self, Error> {
+        let file_duration = seq.blocks.iter().map(|block| block.duration).sum();
+        let soft_delays = apply_soft_delays(&mut seq, &extensions, &options.soft_delays)?;
+
+        let mut blocks: Vec<_> = seq
             .blocks
             .into_iter()
             .scan(0.0, |t_start, block| {
@@ -28,19 +94,201 @@ impl PulseqSequence {
                 Some((tmp, block))
             })
             .collect();
-        // We could check for e.g. lower case fov and if definition is in mm
+        // We could check for e.g. lower case fov
         let fov = seq
             .fov
             .or_else(|| seq.definitions.get("FOV").and_then(|s| parse_fov(s)));
+        // No MRI scanner has a FOV of more than a meter: it is probably in mm
+        let fov_assumed_mm = fov.is_some_and(|(x, y, z)| x.max(y).max(z) > 1.0);
+
+        let shims = blocks
+            .iter()
+            .map(|(_, block)| {
+                let (mag, phase) = block.rf.as_ref()?.shim_shape.as_ref()?;
+                debug_assert_eq!(mag.0.len(), phase.0.len());
+                Some(mag.0.iter().copied().zip(phase.0.iter().copied()).collect())
+            })
+            .collect();
+
+        let labels = extensions.labels(blocks.iter().map(|(_, block)| block.id));
+        let triggers = blocks
+            .iter()
+            .map(|(_, block)| {
+                let mut triggers = extensions.triggers(block.id);
+                triggers.sort_by(|a, b| a.delay.total_cmp(&b.delay));
+                triggers
+            })
+            .collect();
+
+        // Moved out of the blocks, so that the gradients pulseq-rs builds
+        // without their time shapes can't be used by accident
+        let grads = blocks
+            .iter_mut()
+            .map(|(_, block)| {
+                let mut channel = 0;
+                [block.gx.take(), block.gy.take(), block.gz.take()].map(|grad| {
+                    let key = (block.id, channel);
+                    channel += 1;
+                    match shaped_grads.get(&key) {
+                        Some(shaped) => Some(Grad::Shaped(shaped.clone())),
+                        None => grad.map(|grad| Grad::pulseq(grad, first_last.get(&key).copied())),
+                    }
+                })
+            })
+            .collect();
+        let rotations = blocks
+            .iter()
+            .map(|(_, block)| extensions.rotation(block.id))
+            .collect();
+        let rf_blocks = (0..blocks.len())
+            .filter(|&i| blocks[i].1.rf.is_some())
+            .collect();
+        let adc_blocks = (0..blocks.len())
+            .filter(|&i| blocks[i].1.adc.is_some())
+            .collect();
 
-        Self {
+        let mut seq = Self {
             blocks,
             raster: seq.time_raster,
             fov,
+            fov_assumed_mm,
+            definitions: seq.definitions,
+            shims,
+            labels,
+            triggers,
+            extensions,
+            soft_delays,
+            file_duration,
+            rf_info,
+            grads,
+            rotations,
+            grad_moments: Vec::new(),
+            rf_blocks,
+            adc_blocks,
+            interpolation: options.interpolation,
+        };
+
+        let mut grad_moments = vec![GradientMoment::default()];
+        for (block_idx, (block_start, block)) in seq.blocks.iter().enumerate() {
+            let block_end = block_start + block.duration;
+            let [x, y, z] = seq.physical_grad(block_idx, |grad| {
+                helpers::integrate_grad(
+                    grad,
+                    *block_start,
+                    block_end,
+                    *block_start,
+                    seq.raster.grad,
+                )
+            });
+            let last = grad_moments[grad_moments.len() - 1];
+            grad_moments.push(GradientMoment {
+                x: last.x + x,
+                y: last.y + y,
+                z: last.z + z,
+            });
+        }
+        seq.grad_moments = grad_moments;
+
+        seq.check_soft_delays(&options.soft_delays)?;
+        Ok(seq)
+    }
+
+    /// Checks that no block that was changed by a soft delay value is shorter
+    /// than its events, which would make the sequence impossible to play out.
+    fn check_soft_delays(&self, values: &HashMap<String, f64>) -> Result<(), Error> {
+        for (block_idx, (_, block)) in self.blocks.iter().enumerate() {
+            let Some(delay) = self.extensions.soft_delay(block.id) else {
+                continue;
+            };
+            let Some(&value) = values.get(delay.hint) else {
+                continue;
+            };
+            let events_end = EventType::ALL
+                .into_iter()
+                .filter_map(|ty| self.block_encounter(block_idx, ty))
+                .fold(0.0, |end: f64, (_, event_end)| end.max(event_end));
+            if events_end > block.duration + validate::TIME_TOLERANCE {
+                return Err(Error::InvalidSoftDelay {
+                    hint: delay.hint.to_owned(),
+                    value,
+                });
+            }
         }
+        Ok(())
     }
 }
 
+/// Removes POIs that are not after the previous one. The last POI of a block
+/// can coincide with the first one of the next, but `block_start + t` and the
+/// next `block_start` may differ by rounding errors.
+fn dedup_pois(pois: impl Iterator<Item = f64>) -> impl Iterator<Item = f64> {
+    let mut last = f64::NEG_INFINITY;
+    pois.filter(move |&t| {
+        let new = t > last + validate::TIME_TOLERANCE;
+        if new {
+            last = t;
+        }
+        new
+    })
+}
+
+/// Changes the durations of all blocks with a soft delay that has a value in
+/// `values`. Returns all soft delays of the sequence, ordered by their id.
+fn apply_soft_delays(
+    seq: &mut pulseq_rs::Sequence,
+    extensions: &Extensions,
+    values: &HashMap<String, f64>,
+) -> Result<Vec<SoftDelay>, Error> {
+    let block_raster = seq.time_raster.block;
+    let mut soft_delays: Vec<SoftDelay> = Vec::new();
+
+    for block in &mut seq.blocks {
+        let Some(delay) = extensions.soft_delay(block.id) else {
+            continue;
+        };
+        if let Some(existing) = soft_delays.iter().find(|d| d.hint == delay.hint) {
+            // The value can only be converted into one block duration
+            if existing.offset != delay.offset || existing.factor != delay.factor {
+                return Err(Error::InconsistentSoftDelay(delay.hint.to_owned()));
+            }
+        } else {
+            let default = (block.duration - delay.offset) * delay.factor;
+            soft_delays.push(SoftDelay {
+                hint: delay.hint.to_owned(),
+                num: delay.num,
+                offset: delay.offset,
+                factor: delay.factor,
+                default,
+                value: values.get(delay.hint).copied().unwrap_or(default),
+            });
+        }
+
+        if let Some(&value) = values.get(delay.hint) {
+            // Like on the scanner, durations are rounded to the block raster
+            let duration = value / delay.factor + delay.offset;
+            let duration = (duration / block_raster).round() * block_raster;
+            // A factor of 0 results in an infinite duration
+            if !duration.is_finite() || duration < 0.0 {
+                return Err(Error::InvalidSoftDelay {
+                    hint: delay.hint.to_owned(),
+                    value,
+                });
+            }
+            block.duration = duration;
+        }
+    }
+
+    if let Some(hint) = values
+        .keys()
+        .find(|hint| !soft_delays.iter().any(|d| &d.hint == *hint))
+    {
+        return Err(Error::UnknownSoftDelay(hint.clone()));
+    }
+
+    soft_delays.sort_by_key(|d| d.num);
+    Ok(soft_delays)
+}
+
 fn parse_fov(s: &str) -> Option<(f64, f64, f64)> {
     let splits: Vec<_> = s.split_whitespace().collect();
     if splits.len() == 3 {
@@ -59,165 +307,308 @@ impl Backend for PulseqSequence {
         self.fov
     }
 
+    fn rasters(&self) -> Option<Rasters> {
+        Some(Rasters {
+            rf: self.raster.rf,
+            gradient_x: self.raster.grad,
+            gradient_y: self.raster.grad,
+            gradient_z: self.raster.grad,
+            adc: self.raster.adc,
+            block: Some(self.raster.block),
+        })
+    }
+
+    fn labels_at(&self, t: f64) -> Option<Labels> {
+        if self.blocks.is_empty() {
+            None
+        } else {
+            let block_idx = self.block_idx(t);
+            let i = self.labels.partition_point(|&(idx, _)| idx <= block_idx);
+            Some(
+                i.checked_sub(1)
+                    .map_or_else(Labels::default, |i| self.labels[i].1.clone()),
+            )
+        }
+    }
+
+    fn extensions(&self, t_start: f64, t_end: f64) -> Vec<ExtensionEvent> {
+        self.blocks[self.block_idx(t_start)..]
+            .iter()
+            .skip_while(|(block_start, _)| *block_start < t_start)
+            .take_while(|(block_start, _)| *block_start < t_end)
+            .flat_map(|(block_start, block)| {
+                self.extensions.block(block.id).map(|ext| ExtensionEvent {
+                    time: *block_start,
+                    name: ext.name.to_owned(),
+                    id: ext.id,
+                    data: ext
+                        .data
+                        .iter()
+                        .map(|x| x.parse().unwrap_or(f64::NAN))
+                        .collect(),
+                    raw: ext.data.to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    fn adc_readouts(&self, t_start: f64, t_end: f64) -> Box<dyn Iterator<Item = AdcReadout> + '_> {
+        let first = self
+            .adc_blocks
+            .partition_point(|&i| self.adc_start(i) < t_start);
+        Box::new(
+            (first..self.adc_blocks.len())
+                .take_while(move |&index| self.adc_start(self.adc_blocks[index]) < t_end)
+                .map(|index| self.adc_readout(index)),
+        )
+    }
+
+    fn adc_readout_at(&self, t: f64) -> Option<AdcReadout> {
+        let index = self
+            .adc_blocks
+            .partition_point(|&i| self.adc_start(i) <= t)
+            .checked_sub(1)?;
+        let readout = self.adc_readout(index);
+        let adc_end = readout.sample_time(readout.num_samples) - 0.5 * readout.dwell;
+        (t <= adc_end).then_some(readout)
+    }
+
+    fn pulse_info(&self, pulse: (f64, f64)) -> Option<PulseInfo> {
+        let (block_start, block) = self.blocks.get(self.block_idx(pulse.0))?;
+        let rf = block.rf.as_ref()?;
+        let info = self.rf_info.get(&block.id)?;
+
+        let center = block_start + rf.delay + info.center;
+        Some(PulseInfo {
+            usage: info.usage,
+            center,
+            isodelay: block_start + rf.duration(self.raster.rf) - center,
+            estimated: false,
+        })
+    }
+
+    fn validate(&self) -> Vec<ValidationIssue> {
+        validate::validate(self)
+    }
+
+    fn soft_delays(&self) -> Vec<SoftDelay> {
+        self.soft_delays.clone()
+    }
+
+    fn metadata(&self) -> Metadata {
+        let parse = |key: &str| self.definitions.get(key)?.trim().parse().ok();
+
+        Metadata {
+            name: self.definitions.get("Name").map(|s| s.trim().to_owned()),
+            total_duration: parse("TotalDuration"),
+            te: parse("TE"),
+            tr: parse("TR"),
+            fov: self.fov,
+            fov_assumed_mm: self.fov_assumed_mm,
+            nominal_frequency: None,
+            definitions: self.definitions.clone(),
+        }
+    }
+
     fn duration(&self) -> f64 {
         self.blocks.iter().map(|(_, b)| b.duration).sum()
     }
 
-    fn events(&self, ty: EventType, t_start: f64, t_end: f64, max_count: usize) -> Vec<f64> {
+    fn events_iter(
+        &self,
+        ty: EventType,
+        t_start: f64,
+        t_end: f64,
+    ) -> Box<dyn Iterator<Item = f64> + '_> {
         // NOTE: The indirection by using a trait object seems to be neglectable in terms of
         // performance, although it makes the API a bit worse, as the time range that is
         // usually only constructed for the function call now needs a reference.
-        let mut t = t_start;
-        let mut pois = Vec::new();
-        // TODO: this currently is based on the PulseqSequence::next_poi function.
-        // Replace with a more efficient impl that directly fetches a list of samples
-        while let Some(t_next) = self.next_poi(t, ty) {
-            // Important: make t_end exclusive so we don't need to advance by some small value
-            if t_next >= t_end || pois.len() >= max_count {
-                break;
-            }
-            pois.push(t_next);
-            t = t_next + 1e-9;
-        }
+        let idx_start = self.block_idx(t_start);
+        let pois = self.blocks[idx_start..]
+            .iter()
+            .zip(idx_start..)
+            .take_while(move |((block_start, _), _)| *block_start < t_end)
+            .flat_map(move |(&(block_start, _), idx)| {
+                self.block_pois(idx, ty, t_start - block_start)
+                    .into_iter()
+                    .flatten()
+                    .map(move |t| block_start + t)
+            })
+            .skip_while(move |&t| t < t_start)
+            .take_while(move |&t| t < t_end);
 
-        pois
+        Box::new(dedup_pois(pois))
     }
 
-    fn encounter(&self, t_start: f64, ty: EventType) -> Option<(f64, f64)> {
-        let idx_start = match self
-            .blocks
-            .binary_search_by(|(block_start, _)| block_start.total_cmp(&t_start))
-        {
-            Ok(idx) => idx,             // start with the exact match
-            Err(idx) => idx.max(1) - 1, // start before the insertion point
-        };
-
-        for (block_start, block) in &self.blocks[idx_start..] {
-            let t = match ty {
-                EventType::RfPulse => block
-                    .rf
-                    .as_ref()
-                    .map(|rf| (rf.delay, rf.duration(self.raster.rf))),
-                EventType::Adc => block.adc.as_ref().map(|adc| (adc.delay, adc.duration())),
-                EventType::Gradient(channel) => match channel {
-                    GradientChannel::X => block.gx.as_ref(),
-                    GradientChannel::Y => block.gy.as_ref(),
-                    GradientChannel::Z => block.gz.as_ref(),
-                }
-                .map(|grad| (grad.delay(), grad.duration(self.raster.grad))),
-            };
+    fn encounters(
+        &self,
+        ty: EventType,
+        t_start: f64,
+        t_end: f64,
+    ) -> Box<dyn Iterator<Item = (f64, f64)> + '_> {
+        let idx_start = self.block_idx(t_start);
+        Box::new(
+            self.blocks[idx_start..]
+                .iter()
+                .zip(idx_start..)
+                .take_while(move |((block_start, _), _)| *block_start < t_end)
+                .flat_map(move |((block_start, _), idx)| {
+                    self.block_encounters(idx, ty)
+                        .map(move |(delay, dur)| (block_start + delay, block_start + dur))
+                })
+                .filter(move |&(start, _)| t_start <= start && start < t_end),
+        )
+    }
 
-            if let Some((delay, dur)) = t {
-                if block_start + delay >= t_start {
-                    return Some((block_start + delay, block_start + dur));
-                }
+    fn integrate(&self, time: &[f64], moments: &mut MomentVec) {
+        moments.clear();
+        // Single sweep: the gradient moment from 0 to every time point is
+        // calculated once and the moments of the intervalls are the differences
+        let on_resonant = RfOptions::default();
+        let mut prev: Option<(f64, GradientMoment)> = None;
+        for &t in time {
+            let moment = self.grad_moment_at(t);
+            if let Some((t_prev, prev_moment)) = prev {
+                moments.pulse.push(self.rf_moment(t_prev, t, &on_resonant));
+                moments.gradient.push(GradientMoment {
+                    x: moment.x - prev_moment.x,
+                    y: moment.y - prev_moment.y,
+                    z: moment.z - prev_moment.z,
+                });
             }
+            prev = Some((t, moment));
         }
-
-        None
     }
 
-    fn integrate(&self, time: &[f64]) -> Vec<Moment> {
-        let mut moments = Vec::new();
+    fn integrate_rf(&self, time: &[f64], options: &RfOptions, moments: &mut RfPulseMomentVec) {
+        moments.clear();
         for t in time.windows(2) {
-            let (pulse, gradient) = self.integrate(t[0], t[1]);
-            moments.push(Moment { pulse, gradient });
+            moments.push(self.rf_moment(t[0], t[1], options));
         }
-        moments
     }
 
-    fn sample(&self, time: &[f64]) -> Vec<Sample> {
-        time.into_iter()
-            .map(|t| {
-                let (pulse, gradient, adc) = self.sample(*t);
-                Sample {
-                    pulse,
-                    gradient,
-                    adc,
-                }
-            })
-            .collect()
+    fn sample(&self, time: &[f64], samples: &mut SampleVec) {
+        samples.clear();
+        for &t in time {
+            let (pulse, gradient, adc, trigger) = self.sample(t);
+            samples.pulse.push(pulse);
+            samples.gradient.push(gradient);
+            samples.adc.push(adc);
+            samples.trigger.push(trigger);
+        }
+    }
+}
+
+/// POIs of a single event inside of a block, relative to the block start
+enum BlockPois {
+    /// `offset + i * step` for all `i` in `range`
+    Raster {
+        offset: f64,
+        step: f64,
+        range: std::ops::Range<usize>,
+    },
+    /// Explicitly given times, like the vertices of a trapezoid
+    Vertices {
+        times: [f64; 4],
+        range: std::ops::Range<usize>,
+    },
+    /// Sorted POIs of multiple events, e.g. gradients mixed by a rotation
+    List(std::vec::IntoIter<f64>),
+}
+
+impl Iterator for BlockPois {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        match self {
+            BlockPois::Raster {
+                offset,
+                step,
+                range,
+            } => range.next().map(|i| *offset + i as f64 * *step),
+            BlockPois::Vertices { times, range } => range.next().map(|i| times[i]),
+            BlockPois::List(pois) => pois.next(),
+        }
     }
 }
 
-// The old, inefficient single-element methods are moved into this impl block,
-// the trait implementation just loops over it.
-// TODO: replace with code that effectively implements the function signatures
-// given by the Sequence trait
 impl PulseqSequence {
-    fn next_poi(&self, t_start: f64, ty: EventType) -> Option<f64> {
-        let idx_start = match self
+    /// Index of the block containing `t` - or the first block if `t` is negative.
+    fn block_idx(&self, t: f64) -> usize {
+        match self
             .blocks
-            .binary_search_by(|(block_start, _)| block_start.total_cmp(&t_start))
+            .binary_search_by(|(block_start, _)| block_start.total_cmp(&t))
         {
-            Ok(idx) => idx,             // start with the exact match
-            Err(idx) => idx.max(1) - 1, // start before the insertion point
+            Ok(idx) => idx,             // t is exactly at the beginning of the block
+            Err(idx) => idx.max(1) - 1, // t is somewhere in the block before
+        }
+    }
+
+    /// Gradient moment from t = 0 to `t`: the precomputed moment up to the
+    /// start of the block containing `t` plus the integral inside of the block.
+    fn grad_moment_at(&self, t: f64) -> GradientMoment {
+        let block_idx = self.block_idx(t);
+        let Some((block_start, _)) = self.blocks.get(block_idx) else {
+            return GradientMoment::default();
         };
+        let [x, y, z] = self.physical_grad(block_idx, |grad| {
+            helpers::integrate_grad(grad, *block_start, t, *block_start, self.raster.grad)
+        });
 
-        for (block_start, block) in &self.blocks[idx_start..] {
-            // We sample in between samples, so for e.g., a shape of len=10
-            // there will be 0..=10 -> 11 samples.
-            let t = t_start - block_start;
-            let t = match ty {
-                EventType::RfPulse => block.rf.as_ref().map(|rf| {
-                    let idx = ((t - rf.delay) / self.raster.rf)
-                        .clamp(0.0, rf.amp_shape.0.len() as f64)
-                        .ceil();
-                    rf.delay + idx * self.raster.rf
-                }),
-                EventType::Adc => block.adc.as_ref().map(|adc| {
-                    // Here we actually sample in the centers instead of edges because,
-                    // well, that's where the ADC samples are!
-                    let idx = ((t - adc.delay) / adc.dwell - 0.5)
-                        .clamp(0.0, adc.num as f64 - 1.0)
-                        .ceil();
-                    adc.delay + (idx + 0.5) * adc.dwell
-                }),
-                EventType::Gradient(channel) => match channel {
-                    GradientChannel::X => block.gx.as_ref(),
-                    GradientChannel::Y => block.gy.as_ref(),
-                    GradientChannel::Z => block.gz.as_ref(),
-                }
-                .map(|grad| match grad.as_ref() {
-                    Gradient::Free { delay, shape, .. } => {
-                        let idx = ((t - delay) / self.raster.grad)
-                            .clamp(0.0, shape.0.len() as f64)
-                            .ceil();
-                        delay + idx * self.raster.grad
-                    }
-                    &Gradient::Trap {
-                        rise,
-                        flat,
-                        fall,
-                        delay,
-                        ..
-                    } => {
-                        // The four vertices of the trap are its POIs
-                        if t < delay {
-                            delay
-                        } else if t < rise {
-                            delay + rise
-                        } else if t < rise + flat {
-                            delay + rise + flat
-                        } else {
-                            // No if bc. of check below and mandatory else branch
-                            delay + rise + flat + fall
-                        }
-                    }
-                }),
-            };
+        let moment = self.grad_moments[block_idx];
+        GradientMoment {
+            x: moment.x + x,
+            y: moment.y + y,
+            z: moment.z + z,
+        }
+    }
 
-            if let Some(t) = t {
-                if t + block_start >= t_start {
-                    return Some(t + block_start);
-                }
+    /// Evaluates `f` for all (logical) gradients of the block and rotates the
+    /// results into the physical axes, if the block has a rotation.
+    fn physical_grad(&self, block_idx: usize, f: impl Fn(&Grad) -> f64) -> [f64; 3] {
+        let logical = self.grads[block_idx]
+            .each_ref()
+            .map(|grad| grad.as_ref().map_or(0.0, &f));
+
+        match &self.rotations[block_idx] {
+            None => logical,
+            Some(rot) => {
+                rot.map(|row| row[0] * logical[0] + row[1] * logical[1] + row[2] * logical[2])
             }
         }
+    }
 
-        None
+    /// The (logical) gradients of the block that contribute to the given
+    /// physical channel. Without rotation, this is only the gradient of the channel.
+    fn channel_grads(
+        &self,
+        block_idx: usize,
+        channel: GradientChannel,
+    ) -> impl Iterator<Item = &Grad> {
+        // Matrix elements below this are treated as zero. Rotations converted
+        // from quaternions are not exact, e.g. cos(90°) is 6e-17.
+        const ROTATION_TOLERANCE: f64 = 1e-9;
+
+        let i = match channel {
+            GradientChannel::X => 0,
+            GradientChannel::Y => 1,
+            GradientChannel::Z => 2,
+        };
+        let rotation = &self.rotations[block_idx];
+
+        self.grads[block_idx]
+            .iter()
+            .enumerate()
+            .filter(move |&(j, _)| match rotation {
+                None => i == j,
+                Some(rot) => rot[i][j].abs() > ROTATION_TOLERANCE,
+            })
+            .filter_map(|(_, grad)| grad.as_ref())
     }
 
-    fn integrate(&self, mut t_start: f64, mut t_end: f64) -> (RfPulseMoment, GradientMoment) {
+    /// Integrates the RF pulses from `t_start` to `t_end`, only visiting the
+    /// blocks that contain a pulse. If `t_end < t_start`, integrates backwards.
+    fn rf_moment(&self, mut t_start: f64, mut t_end: f64, options: &RfOptions) -> RfPulseMoment {
         let mut sign = 1.0;
         if t_end < t_start {
             // Integrate backwards and flip sign
@@ -225,70 +616,232 @@ impl PulseqSequence {
             sign = -1.0;
         }
 
-        let idx_start = match self
-            .blocks
-            .binary_search_by(|(block_start, _)| block_start.total_cmp(&t_start))
-        {
-            Ok(idx) => idx,             // start with the exact match
-            Err(idx) => idx.max(1) - 1, // start before the insertion point
-        };
+        // Skip all pulses that end before t_start
+        let first = self.rf_blocks.partition_point(|&i| {
+            let (block_start, block) = &self.blocks[i];
+            block_start + block.duration <= t_start
+        });
 
-        let mut spin = util::Spin::relaxed();
-        let mut grad = GradientMoment {
-            x: 0.0,
-            y: 0.0,
-            z: 0.0,
-        };
-        for (block_start, block) in &self.blocks[idx_start..] {
+        let mut rotation = Rotation::IDENTITY;
+        for &i in &self.rf_blocks[first..] {
+            let (block_start, block) = &self.blocks[i];
             if *block_start >= t_end {
                 break;
             }
-            if let Some(gx) = block.gx.as_ref() {
-                grad.x += helpers::integrate_grad(
-                    gx.as_ref(),
-                    t_start,
-                    t_end,
-                    *block_start,
-                    self.raster.grad,
-                );
-            }
-            if let Some(gy) = block.gy.as_ref() {
-                grad.y += helpers::integrate_grad(
-                    gy.as_ref(),
-                    t_start,
-                    t_end,
-                    *block_start,
-                    self.raster.grad,
-                );
-            }
-            if let Some(gz) = block.gz.as_ref() {
-                grad.z += helpers::integrate_grad(
-                    gz.as_ref(),
-                    t_start,
-                    t_end,
-                    *block_start,
-                    self.raster.grad,
-                );
-            }
-            if let Some(rf) = block.rf.as_ref() {
-                helpers::integrate_rf(rf, &mut spin, t_start, t_end, *block_start, self.raster.rf);
-            }
+            let Some(rf) = block.rf.as_ref() else {
+                continue;
+            };
+
+            // Off-resonance including the one created by the gradients of this block
+            let off_resonance = |t: f64| {
+                let Some((x, y, z)) = options.position else {
+                    return options.off_resonance;
+                };
+                let [gx, gy, gz] = self.physical_grad(i, |grad| {
+                    helpers::sample_grad(
+                        t - block_start,
+                        grad,
+                        self.raster.grad,
+                        self.interpolation,
+                    )
+                });
+                options.off_resonance + gx * x + gy * y + gz * z
+            };
+            let frame = helpers::RfFrame {
+                apply_freq_offset: options.apply_freq_offset,
+                off_resonance: &off_resonance,
+            };
+
+            helpers::integrate_rf(
+                rf,
+                &mut rotation,
+                t_start,
+                t_end,
+                *block_start,
+                self.raster.rf,
+                (!options.is_on_resonant()).then_some(&frame),
+            );
         }
 
-        (
+        let moment = util::rf_moment(rotation);
+        if sign < 0.0 {
             RfPulseMoment {
-                angle: sign * spin.angle(),
-                phase: sign * spin.phase(),
-            },
-            GradientMoment {
-                x: sign * grad.x,
-                y: sign * grad.y,
-                z: sign * grad.z,
+                angle: -moment.angle,
+                phase: -moment.phase,
+                rotation: moment.rotation.inverse(),
+            }
+        } else {
+            moment
+        }
+    }
+
+    /// Start time of the ADC in the given block, which must contain an ADC
+    fn adc_start(&self, block_idx: usize) -> f64 {
+        let (block_start, block) = &self.blocks[block_idx];
+        block_start + block.adc.as_ref().map_or(0.0, |adc| adc.delay)
+    }
+
+    /// Readout of the ADC with the given index into `adc_blocks`
+    fn adc_readout(&self, index: usize) -> AdcReadout {
+        let block_idx = self.adc_blocks[index];
+        let adc = self.blocks[block_idx].1.adc.as_ref().unwrap();
+        AdcReadout {
+            index,
+            // Samples are in the centers of the dwell times, like the POIs
+            start: self.adc_start(block_idx) + 0.5 * adc.dwell,
+            num_samples: adc.num as usize,
+            dwell: adc.dwell,
+            phase: adc.phase,
+            frequency: adc.freq,
+        }
+    }
+
+    /// All POIs of the given type in the block, relative to the block start.
+    /// Raster points before `t_skip` are skipped, but a few earlier ones might
+    /// still be returned. The POIs are:
+    /// - RF: the edges of all samples, so one more than the shape length
+    /// - ADC: the centers of all samples
+    /// - free gradients: the edges of all samples, like for RF
+    /// - trapezoids: the four vertices
+    /// - gradients with a time shape: all vertices
+    /// - triggers: start and end
+    fn block_pois(&self, block_idx: usize, ty: EventType, t_skip: f64) -> Option<BlockPois> {
+        let block = &self.blocks[block_idx].1;
+        // First raster index that can be at or after t_skip. One sample
+        // earlier is included to be robust against rounding errors.
+        let first =
+            |offset: f64, step: f64| (((t_skip - offset) / step).ceil() - 1.0).max(0.0) as usize;
+        let raster = |offset: f64, step: f64, count: usize| BlockPois::Raster {
+            offset,
+            step,
+            range: first(offset, step).min(count)..count,
+        };
+
+        match ty {
+            EventType::RfPulse => block
+                .rf
+                .as_ref()
+                .map(|rf| raster(rf.delay, self.raster.rf, rf.amp_shape.0.len() + 1)),
+            EventType::Adc => block.adc.as_ref().map(|adc| {
+                // Here we actually sample in the centers instead of edges because,
+                // well, that's where the ADC samples are!
+                raster(adc.delay + 0.5 * adc.dwell, adc.dwell, adc.num as usize)
+            }),
+            EventType::Gradient(channel) => {
+                let grad_pois = |grad: &Grad| match grad {
+                    Grad::Shaped(grad) => BlockPois::List(
+                        grad.times
+                            .iter()
+                            .map(|t| grad.delay + t)
+                            .collect::<Vec<_>>()
+                            .into_iter(),
+                    ),
+                    Grad::Pulseq { grad, .. } => match **grad {
+                        Gradient::Free {
+                            delay, ref shape, ..
+                        } => raster(delay, self.raster.grad, shape.0.len() + 1),
+                        Gradient::Trap {
+                            rise,
+                            flat,
+                            fall,
+                            delay,
+                            ..
+                        } => BlockPois::Vertices {
+                            times: [
+                                delay,
+                                delay + rise,
+                                delay + rise + flat,
+                                delay + rise + flat + fall,
+                            ],
+                            range: 0..4,
+                        },
+                    },
+                };
+
+                // Rotated blocks can mix multiple gradients into one channel
+                let mut grads = self.channel_grads(block_idx, channel);
+                let first = grad_pois(grads.next()?);
+                match grads.next() {
+                    None => Some(first),
+                    Some(second) => {
+                        let mut pois: Vec<f64> = first
+                            .chain(grad_pois(second))
+                            .chain(grads.flat_map(grad_pois))
+                            .collect();
+                        pois.sort_by(f64::total_cmp);
+                        pois.dedup();
+                        Some(BlockPois::List(pois.into_iter()))
+                    }
+                }
+            }
+            EventType::Trigger => match self.triggers[block_idx].as_slice() {
+                [] => None,
+                [trigger] => Some(BlockPois::Vertices {
+                    times: [trigger.delay, trigger.delay + trigger.duration, 0.0, 0.0],
+                    range: 0..2,
+                }),
+                triggers => {
+                    let mut pois: Vec<f64> = triggers
+                        .iter()
+                        .flat_map(|trigger| [trigger.delay, trigger.delay + trigger.duration])
+                        .collect();
+                    pois.sort_by(f64::total_cmp);
+                    pois.dedup();
+                    Some(BlockPois::List(pois.into_iter()))
+                }
             },
-        )
+        }
     }
 
-    fn sample(&self, t: f64) -> (RfPulseSample, GradientSample, AdcBlockSample) {
+    /// Start and end of all encounters of the given type relative to the block
+    /// start. Only triggers can have more than one per block.
+    fn block_encounters(
+        &self,
+        block_idx: usize,
+        ty: EventType,
+    ) -> impl Iterator<Item = (f64, f64)> + '_ {
+        let (single, triggers) = match ty {
+            EventType::Trigger => (None, self.triggers[block_idx].as_slice()),
+            _ => (self.block_encounter(block_idx, ty), &[][..]),
+        };
+        let triggers = triggers
+            .iter()
+            .map(|trigger| (trigger.delay, trigger.delay + trigger.duration));
+        single.into_iter().chain(triggers)
+    }
+
+    /// Start and end of the event of the given type relative to the block
+    /// start. For triggers, this spans all triggers of the block.
+    fn block_encounter(&self, block_idx: usize, ty: EventType) -> Option<(f64, f64)> {
+        let block = &self.blocks[block_idx].1;
+        match ty {
+            EventType::RfPulse => block
+                .rf
+                .as_ref()
+                .map(|rf| (rf.delay, rf.duration(self.raster.rf))),
+            EventType::Adc => block.adc.as_ref().map(|adc| (adc.delay, adc.duration())),
+            EventType::Gradient(channel) => self
+                .channel_grads(block_idx, channel)
+                .map(|grad| (grad.delay(), grad.duration(self.raster.grad)))
+                .reduce(|(start_a, end_a), (start_b, end_b)| {
+                    (start_a.min(start_b), end_a.max(end_b))
+                }),
+            EventType::Trigger => self.triggers[block_idx]
+                .iter()
+                .map(|trigger| (trigger.delay, trigger.delay + trigger.duration))
+                .reduce(|(start_a, end_a), (start_b, end_b)| {
+                    (start_a.min(start_b), end_a.max(end_b))
+                }),
+        }
+    }
+}
+
+// The old, inefficient single-element sample method is kept in this impl block,
+// the trait implementation just loops over it.
+// TODO: replace with code that effectively implements the function signature
+// given by the Sequence trait
+impl PulseqSequence {
+    fn sample(&self, t: f64) -> (RfPulseSample, GradientSample, AdcBlockSample, TriggerSample) {
         let block_idx = match self
             .blocks
             .binary_search_by(|(block_start, _)| block_start.total_cmp(&t))
@@ -298,34 +851,29 @@ impl PulseqSequence {
         };
         let (block_start, block) = &self.blocks[block_idx];
 
-        let pulse_sample = if let Some(rf) = &block.rf {
-            let index = ((t - block_start - rf.delay) / self.raster.rf - 0.5).ceil() as usize;
-            if index < rf.amp_shape.0.len() {
-                let shim = rf.shim_shape.as_ref().map(|(mag, phase)| {
-                    assert_eq!(mag.0.len(), phase.0.len());
-                    mag.0.iter().zip(&phase.0).map(|(&m, &p)| (m, p)).collect()
-                });
-                RfPulseSample {
-                    amplitude: rf.amp * rf.amp_shape.0[index],
-                    phase: rf.phase + rf.phase_shape.0[index] * std::f64::consts::TAU,
+        let pulse_sample = block
+            .rf
+            .as_ref()
+            .and_then(|rf| {
+                let x = (t - block_start - rf.delay) / self.raster.rf;
+                let len = rf.amp_shape.0.len().min(rf.phase_shape.0.len());
+                let (amplitude, phase) = self.interpolation.sample_polar(len, x, |i| {
+                    (
+                        rf.amp * rf.amp_shape.0[i],
+                        rf.phase + rf.phase_shape.0[i] * std::f64::consts::TAU,
+                    )
+                })?;
+                Some(RfPulseSample {
+                    amplitude,
+                    phase,
                     frequency: rf.freq,
-                    shim,
-                }
-            } else {
-                RfPulseSample::default()
-            }
-        } else {
-            RfPulseSample::default()
-        };
+                    shim: self.shims[block_idx].clone(),
+                })
+            })
+            .unwrap_or_default();
 
-        let x = block.gx.as_ref().map_or(0.0, |gx| {
-            helpers::sample_grad(t - block_start, gx.as_ref(), self.raster.grad)
-        });
-        let y = block.gy.as_ref().map_or(0.0, |gy| {
-            helpers::sample_grad(t - block_start, gy.as_ref(), self.raster.grad)
-        });
-        let z = block.gz.as_ref().map_or(0.0, |gz| {
-            helpers::sample_grad(t - block_start, gz.as_ref(), self.raster.grad)
+        let [x, y, z] = self.physical_grad(block_idx, |grad| {
+            helpers::sample_grad(t - block_start, grad, self.raster.grad, self.interpolation)
         });
 
         let adc_sample = if let Some(adc) = &block.adc {
@@ -334,7 +882,8 @@ impl PulseqSequence {
             {
                 AdcBlockSample {
                     active: true,
-                    phase: adc.phase - adc.freq * std::f64::consts::TAU * (t - *block_start - adc.delay),
+                    phase: adc.phase
+                        - adc.freq * std::f64::consts::TAU * (t - *block_start - adc.delay),
                     frequency: adc.freq,
                 }
             } else {
@@ -344,6 +893,537 @@ impl PulseqSequence {
             AdcBlockSample::default()
         };
 
-        (pulse_sample, GradientSample { x, y, z }, adc_sample)
+        // If triggers overlap, the one that starts first is returned
+        let trigger_sample = self.triggers[block_idx]
+            .iter()
+            .find(|trigger| {
+                block_start + trigger.delay <= t
+                    && t <= block_start + trigger.delay + trigger.duration
+            })
+            .map_or_else(TriggerSample::default, |trigger| TriggerSample {
+                active: true,
+                input: trigger.input,
+                channel: trigger.channel,
+                duration: trigger.duration,
+            });
+
+        (
+            pulse_sample,
+            GradientSample { x, y, z },
+            adc_sample,
+            trigger_sample,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dedup_pois, validate, PulseqSequence};
+    use crate::{
+        Backend, EventType, GradientChannel, Interpolation, LoadOptions, PulseUsage, RfOptions,
+    };
+    use assert2::check;
+    use std::collections::HashMap;
+
+    const GRE: &str = include_str!("../../examples/gre.seq");
+    const SE_1_5: &str = include_str!("../../examples/se_1.5.seq");
+
+    #[test]
+    fn metadata() {
+        let meta = PulseqSequence::from_source(GRE).unwrap().metadata();
+        check!(meta.name.as_deref() == Some("gre"));
+        check!(meta.total_duration == Some(3.072));
+        check!(meta.fov == Some((0.256, 0.256, 0.003)));
+        check!(!meta.fov_assumed_mm);
+        check!(meta.definitions.contains_key("GradientRasterTime"));
+
+        let source = GRE.replace("FOV 0.256 0.256 0.003", "FOV 256 256 3");
+        let meta = PulseqSequence::from_source(&source).unwrap().metadata();
+        check!(meta.fov_assumed_mm);
+        // The FOV is returned as stored, users decide about the unit
+        check!(meta.fov == Some((256.0, 256.0, 3.0)));
+    }
+
+    #[test]
+    fn custom_extensions() {
+        // Attach a custom extension to the second block
+        let source = GRE.replacen(
+            "   2 100   0   2   3   4  0  0",
+            "   2 100   0   2   3   4  0  1",
+            1,
+        ) + "\n[EXTENSIONS]\n1 7 3 0\n\nextension NAVIGATOR 7\n3 1.5 abc\n";
+        let seq = PulseqSequence::from_source(&source).unwrap();
+
+        let exts = seq.extensions(0.0, f64::INFINITY);
+        check!(exts.len() == 1);
+        check!(exts[0].name == "NAVIGATOR");
+        check!(exts[0].id == 3);
+        check!(exts[0].data[0] == 1.5);
+        check!(exts[0].data[1].is_nan());
+        check!(exts[0].raw == ["1.5", "abc"]);
+        check!((exts[0].time - 3.17e-3).abs() < 1e-12);
+        check!(seq.extensions(0.0, 3e-3).is_empty());
+    }
+
+    #[test]
+    fn rotations() {
+        // Rotate the phase encoder (block 2) by 45° around x and the readout
+        // (block 4) by 90° around z
+        let source = GRE
+            .replacen(
+                "   2 100   0   2   3   4  0  0",
+                "   2 100   0   2   3   4  0  1",
+                1,
+            )
+            .replacen(
+                "   4 330   0   5   0   0  1  0",
+                "   4 330   0   5   0   0  1  2",
+                1,
+            )
+            + "\n[EXTENSIONS]\n1 1 1 0\n2 1 2 0\n\nextension ROTATIONS 1\n\
+               1 1 0 0 0 0.70710678118654752 -0.70710678118654752 0 0.70710678118654752 0.70710678118654752\n\
+               2 0.70710678118654752 0 0 0.70710678118654752\n";
+        let seq = PulseqSequence::from_source(&source).unwrap();
+        let gx = EventType::Gradient(GradientChannel::X);
+        let gy = EventType::Gradient(GradientChannel::Y);
+
+        // The readout gradient is played out on y
+        let readout = (4.95e-3, 8.25e-3);
+        let (_, grad, _, _) = seq.sample(6.6e-3);
+        check!(grad.x.abs() < 1e-6);
+        check!((grad.y - 312500.0).abs() < 1e-6);
+
+        let mut moments = crate::MomentVec::default();
+        seq.integrate(&[readout.0, readout.1], &mut moments);
+        check!(moments.gradient.x[0].abs() < 1e-6);
+        check!((moments.gradient.y[0] - 312500.0 * 3250e-6).abs() < 1e-6);
+
+        // The next x gradient is the spoiler of block 5
+        let (start, _) = seq.encounter(readout.0, gx).unwrap();
+        check!((start - readout.1).abs() < 1e-12);
+        let (start, end) = seq.encounter(readout.0, gy).unwrap();
+        check!((start - readout.0).abs() < 1e-12 && (end - 8.25e-3).abs() < 1e-12);
+
+        // y of the phase encoder mixes the y (90/820/90 us) and z (130/740/130 us) trapezoids
+        let pois: Vec<f64> = seq.events_iter(gy, 3.17e-3, 4.2e-3).collect();
+        let expected = [0.0, 90e-6, 130e-6, 870e-6, 910e-6, 1000e-6];
+        check!(pois.len() == expected.len());
+        for (poi, expected) in pois.iter().zip(expected) {
+            check!((poi - 3.17e-3 - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn soft_delays() {
+        // Make the TE fill delay (block 3, 780 us) a soft delay
+        let source = GRE.replacen(
+            "   3  78   0   0   0   0  0  0",
+            "   3  78   0   0   0   0  0  1",
+            1,
+        ) + "\n[EXTENSIONS]\n1 1 1 0\n\nextension DELAYS 1\n1 1 -200 2 TE\n";
+        let default = PulseqSequence::from_source(&source).unwrap();
+        let delays = default.soft_delays();
+        check!(delays.len() == 1);
+        check!(delays[0].hint == "TE");
+        check!((delays[0].offset + 200e-6).abs() < 1e-12);
+        check!((delays[0].default - 1.96e-3).abs() < 1e-12);
+        check!(delays[0].value == delays[0].default);
+
+        let values = HashMap::from([("TE".to_owned(), 3.96e-3)]);
+        let options = |soft_delays| LoadOptions {
+            soft_delays,
+            ..Default::default()
+        };
+        let longer = PulseqSequence::from_source_with_options(&source, &options(values)).unwrap();
+        check!((longer.duration() - default.duration() - 1e-3).abs() < 1e-9);
+        check!(longer.soft_delays()[0].value == 3.96e-3);
+        let adc = |seq: &PulseqSequence| seq.encounter(0.0, EventType::Adc).unwrap().0;
+        check!((adc(&longer) - adc(&default) - 1e-3).abs() < 1e-9);
+
+        let values = HashMap::from([("TR".to_owned(), 1.0)]);
+        check!(let Err(crate::Error::UnknownSoftDelay(_)) =
+            PulseqSequence::from_source_with_options(&source, &options(values)));
+        let values = HashMap::from([("TE".to_owned(), 0.0)]);
+        check!(let Err(crate::Error::InvalidSoftDelay { .. }) =
+            PulseqSequence::from_source_with_options(&source, &options(values)));
+        let zero_factor = source.replacen("1 1 -200 2 TE", "1 1 -200 0 TE", 1);
+        let values = HashMap::from([("TE".to_owned(), 1e-3)]);
+        check!(let Err(crate::Error::InvalidSoftDelay { .. }) =
+            PulseqSequence::from_source_with_options(&zero_factor, &options(values)));
+
+        // Second TE delay (block 8) that converts the value differently
+        let inconsistent =
+            GRE.replacen(
+                "   3  78   0   0   0   0  0  0",
+                "   3  78   0   0   0   0  0  1",
+                1,
+            )
+            .replacen(
+                "   8  78   0   0   0   0  0  0",
+                "   8  78   0   0   0   0  0  2",
+                1,
+            ) + "\n[EXTENSIONS]\n1 1 1 0\n2 1 2 0\n\n\
+               extension DELAYS 1\n1 1 -200 2 TE\n2 1 -100 2 TE\n";
+        check!(let Err(crate::Error::InconsistentSoftDelay(_)) =
+            PulseqSequence::from_source(&inconsistent));
+
+        // The readout (block 4, 3.3 ms) can't be shorter than its gradient and ADC
+        let source = GRE.replacen(
+            "   4 330   0   5   0   0  1  0",
+            "   4 330   0   5   0   0  1  1",
+            1,
+        ) + "\n[EXTENSIONS]\n1 1 1 0\n\nextension DELAYS 1\n1 1 0 1 RO\n";
+        let values = HashMap::from([("RO".to_owned(), 3.2e-3)]);
+        check!(let Err(crate::Error::InvalidSoftDelay { .. }) =
+            PulseqSequence::from_source_with_options(&source, &options(values)));
+        let values = HashMap::from([("RO".to_owned(), 3.3e-3)]);
+        check!(PulseqSequence::from_source_with_options(&source, &options(values)).is_ok());
+    }
+
+    #[test]
+    fn labels() {
+        // Increment LIN with the ADCs of the first two TRs (blocks 4 and 9)
+        let source =
+            GRE.replacen(
+                "   4 330   0   5   0   0  1  0",
+                "   4 330   0   5   0   0  1  1",
+                1,
+            )
+            .replacen(
+                "   9 330   0   5   0   0  2  0",
+                "   9 330   0   5   0   0  2  1",
+                1,
+            ) + "\n[EXTENSIONS]\n1 1 1 0\n\nextension LABELINC 1\n1 1 LIN\n";
+        let seq = PulseqSequence::from_source(&source).unwrap();
+        let lin = |t: f64| seq.labels_at(t).unwrap().get("LIN");
+
+        check!(lin(0.0) == 0);
+        check!(lin(4.95e-3) == 1);
+        check!(lin(15e-3) == 1);
+        check!(lin(16.95e-3) == 2);
+        check!(lin(seq.duration()) == 2);
+    }
+
+    #[test]
+    fn triggers() {
+        // Physio wait and an output trigger in the TE delay (block 3)
+        let source = GRE.replacen(
+            "   3  78   0   0   0   0  0  0",
+            "   3  78   0   0   0   0  0  1",
+            1,
+        ) + "\n[EXTENSIONS]\n1 1 1 2\n2 1 2 0\n\n\
+             extension TRIGGERS 1\n1 2 1 0 100\n2 1 3 500 100\n";
+        let seq = PulseqSequence::from_source(&source).unwrap();
+        let block_start = 4.17e-3;
+
+        let pois: Vec<f64> = seq
+            .events_iter(EventType::Trigger, 0.0, seq.duration())
+            .collect();
+        let expected = [0.0, 100e-6, 500e-6, 600e-6];
+        check!(pois.len() == expected.len());
+        for (poi, expected) in pois.iter().zip(expected) {
+            check!((poi - block_start - expected).abs() < 1e-12);
+        }
+        // One encounter per trigger, even though they are in the same block
+        let encounters: Vec<_> = seq
+            .encounters(EventType::Trigger, 0.0, f64::INFINITY)
+            .collect();
+        check!(encounters.len() == 2);
+        check!((encounters[0].0 - block_start).abs() < 1e-12);
+        check!((encounters[0].1 - block_start - 100e-6).abs() < 1e-12);
+        check!((encounters[1].0 - block_start - 500e-6).abs() < 1e-12);
+        check!((encounters[1].1 - block_start - 600e-6).abs() < 1e-12);
+
+        let input = seq.sample(block_start + 50e-6).3;
+        check!(input.active && input.input && input.channel == 1);
+        let output = seq.sample(block_start + 550e-6).3;
+        check!(output.active && !output.input && output.channel == 3);
+        check!(!seq.sample(block_start + 300e-6).3.active);
+    }
+
+    #[test]
+    fn time_shaped_gradient() {
+        // Trapezoid as free gradient with a time shape in the TE delay (block 3):
+        // 100 us delay, then vertices at 0, 100, 300 and 400 us
+        let source = GRE
+            .replacen(
+                "   3  78   0   0   0   0  0  0",
+                "   3  78   0 999   0   0  0  0",
+                1,
+            )
+            .replacen(
+                "[SIGNATURE]",
+                "shape_id 998\nnum_samples 4\n0\n1\n1\n0\n\n\
+                 shape_id 999\nnum_samples 4\n0\n10\n30\n40\n\n\
+                 [GRADIENTS]\n999 100000 998 999 100\n\n[SIGNATURE]",
+                1,
+            );
+        let seq = PulseqSequence::from_source(&source).unwrap();
+        let gx = EventType::Gradient(GradientChannel::X);
+        let block_start = 4.17e-3;
+
+        for (t, expected) in [(50e-6, 0.0), (150e-6, 5e4), (300e-6, 1e5), (450e-6, 5e4)] {
+            check!((seq.sample(block_start + t).1.x - expected).abs() < 1e-6);
+        }
+        check!(seq.sample(block_start + 550e-6).1.x == 0.0);
+
+        let mut moment = crate::MomentVec::default();
+        seq.integrate(&[block_start, block_start + 780e-6], &mut moment);
+        check!((moment.gradient.x[0] - 30.0).abs() < 1e-9);
+
+        let (start, end) = seq.encounter(block_start, gx).unwrap();
+        check!((start - block_start - 100e-6).abs() < 1e-12);
+        check!((end - block_start - 500e-6).abs() < 1e-12);
+        let pois: Vec<f64> = seq
+            .events_iter(gx, block_start + 1e-6, block_start + 780e-6)
+            .collect();
+        let expected = [100e-6, 200e-6, 400e-6, 500e-6];
+        check!(pois.len() == expected.len());
+        for (poi, expected) in pois.iter().zip(expected) {
+            check!((poi - block_start - expected).abs() < 1e-12);
+        }
+        check!(seq.validate().is_empty());
+    }
+
+    #[test]
+    fn free_gradient_integration() {
+        // Free gradient with the samples 1, 2, 3, 4 at the start of the TE delay (block 3)
+        let source = GRE
+            .replacen(
+                "   3  78   0   0   0   0  0  0",
+                "   3  78   0 997   0   0  0  0",
+                1,
+            )
+            .replacen(
+                "[SIGNATURE]",
+                "shape_id 997\nnum_samples 4\n1\n2\n3\n4\n\n\
+                 [GRADIENTS]\n997 1000 997 0 0\n\n[SIGNATURE]",
+                1,
+            );
+        let seq = PulseqSequence::from_source(&source).unwrap();
+        let block_start = 4.17e-3;
+        let integrate = |t_start: f64, t_end: f64| {
+            let mut moment = crate::MomentVec::default();
+            seq.integrate(&[block_start + t_start, block_start + t_end], &mut moment);
+            moment.gradient.x[0]
+        };
+
+        // Linear between the raster centers and extrapolated to the edges,
+        // which is the ramp 0.5 -> 4.5
+        check!((integrate(0.0, 10e-6) - 1000.0 * 10e-6 * 1.0).abs() < 1e-12);
+        check!((integrate(10e-6, 25e-6) - 1000.0 * 10e-6 * 3.375).abs() < 1e-12);
+        check!((integrate(0.0, 780e-6) - 1000.0 * 10e-6 * 10.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn stored_first_last() {
+        // Split gradient: 0 | 0.5 1 1 1 | 1 | 1 1 1 0.5 | 0 (x 100 mT/m), the
+        // extrapolation would start and end at 0.25 instead
+        let source = include_str!("../../examples/split_1.5.seq");
+        let seq = PulseqSequence::from_source(source).unwrap();
+        check!(seq.validate().is_empty());
+        let integrate = |t_start: f64, t_end: f64| {
+            let mut moment = crate::MomentVec::default();
+            seq.integrate(&[t_start, t_end], &mut moment);
+            moment.gradient.x[0]
+        };
+
+        // One raster cell of the full amplitude is 1 / m
+        let (split, end) = (1.24e-3, 1.28e-3);
+        check!((integrate(1.2e-3, split) - 3.375).abs() < 1e-9);
+        check!((integrate(split, end) - 3.375).abs() < 1e-9);
+        let adc = seq.events(EventType::Adc, split, end, usize::MAX);
+        let expected = [0.5, 1.5, 2.5, 3.25];
+        check!(adc.len() == expected.len());
+        for (t, expected) in adc.into_iter().zip(expected) {
+            check!((integrate(split, t) - expected).abs() < 1e-9);
+        }
+
+        // With the extrapolated first amplitude, the gradient jumps at the start
+        let source = source.replacen("1 100000      0", "1 100000  25000", 1);
+        let seq = PulseqSequence::from_source(&source).unwrap();
+        check!(seq.validate().len() == 1);
+        let mut moment = crate::MomentVec::default();
+        seq.integrate(&[1.2e-3, split], &mut moment);
+        check!((moment.gradient.x[0] - 3.4375).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sample_positions() {
+        // Free gradient with the samples 1, 2, 3, 4 at the start of the TE delay (block 3)
+        let source = GRE
+            .replacen(
+                "   3  78   0   0   0   0  0  0",
+                "   3  78   0 997   0   0  0  0",
+                1,
+            )
+            .replacen(
+                "[SIGNATURE]",
+                "shape_id 997\nnum_samples 4\n1\n2\n3\n4\n\n\
+                 [GRADIENTS]\n997 1000 997 0 0\n\n[SIGNATURE]",
+                1,
+            );
+        let seq = PulseqSequence::from_source(&source).unwrap();
+        let block_start = 4.17e-3;
+        let sample = |t: f64| seq.sample(block_start + t).1.x;
+
+        // Sample i holds for [i, i + 1) raster cells, not [i - 0.5, i + 0.5)
+        check!(sample(4e-6) == 1000.0);
+        check!(sample(6e-6) == 1000.0);
+        check!(sample(14e-6) == 2000.0);
+        check!(sample(39e-6) == 4000.0);
+        check!(sample(41e-6) == 0.0);
+    }
+
+    #[test]
+    fn interpolation() {
+        let load = |interpolation| {
+            let options = LoadOptions {
+                interpolation,
+                ..Default::default()
+            };
+            PulseqSequence::from_source_with_options(GRE, &options).unwrap()
+        };
+        let nearest = load(Interpolation::Nearest);
+        let linear = load(Interpolation::Linear);
+        let amplitude = |seq: &PulseqSequence, t: f64| seq.sample(t).0.amplitude;
+
+        // First pulse: 100 us delay, samples on a 1 us raster
+        check!(amplitude(&nearest, 99.9e-6) == 0.0);
+        let center = |i: f64| 100e-6 + (i + 0.5) * 1e-6;
+        for i in [0.0, 700.0, 1499.0] {
+            check!((amplitude(&linear, center(i)) - amplitude(&nearest, center(i))).abs() < 1e-9);
+            let mean =
+                0.5 * (amplitude(&nearest, center(i)) + amplitude(&nearest, center(i + 1.0)));
+            check!((amplitude(&linear, center(i + 0.5)) - mean).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn integrate_windows() {
+        let seq = PulseqSequence::from_source(GRE).unwrap();
+        let duration = seq.duration();
+        let time: Vec<f64> = (0..=1000).map(|i| i as f64 * duration / 1000.0).collect();
+
+        let mut windows = crate::MomentVec::default();
+        seq.integrate(&time, &mut windows);
+        let mut total = crate::MomentVec::default();
+        seq.integrate(&[0.0, duration], &mut total);
+        let mut backwards = crate::MomentVec::default();
+        seq.integrate(&[duration, 0.0], &mut backwards);
+
+        let sum: f64 = windows.gradient.x.iter().sum();
+        check!(windows.len() == 1000);
+        check!((sum - total.gradient.x[0]).abs() < 1e-6 * total.gradient.x[0].abs());
+        check!(backwards.gradient.x[0] == -total.gradient.x[0]);
+    }
+
+    #[test]
+    fn trap_pois_include_delay() {
+        let seq = PulseqSequence::from_source(GRE).unwrap();
+        let gz = EventType::Gradient(GradientChannel::Z);
+
+        // First block: trapezoid with 30 us delay, 70 us rise and 3000 us flat top
+        let pois: Vec<f64> = seq.events_iter(gz, 0.0, 3.15e-3).collect();
+        let expected = [30e-6, 100e-6, 3100e-6];
+        check!(pois.len() == expected.len());
+        for (poi, expected) in pois.iter().zip(expected) {
+            check!((poi - expected).abs() < 1e-12);
+        }
+
+        // Starting after the end of the flat top without delay must not skip it
+        let next = seq.events_iter(gz, 3.08e-3, f64::INFINITY).next().unwrap();
+        check!((next - 3100e-6).abs() < 1e-12);
+    }
+
+    #[test]
+    fn events_strictly_increasing() {
+        let seq = PulseqSequence::from_source(GRE).unwrap();
+        for ty in EventType::ALL {
+            let events: Vec<f64> = seq.events_iter(ty, 0.0, f64::INFINITY).collect();
+            // Also no near duplicates from rounding errors at block boundaries
+            check!(events
+                .windows(2)
+                .all(|t| t[1] - t[0] > validate::TIME_TOLERANCE));
+        }
+
+        let block_end = 0.1 + 0.2;
+        let pois: Vec<f64> = dedup_pois([0.1, block_end, 0.3, 0.4].into_iter()).collect();
+        check!(block_end != 0.3);
+        check!(pois == [0.1, block_end, 0.4]);
+    }
+
+    #[test]
+    fn off_resonant_rf() {
+        let seq = PulseqSequence::from_source(GRE).unwrap();
+        // First pulse, played out during the flat top of a 444444 Hz/m slice gradient
+        let time = [0.0, 3.17e-3];
+        let integrate = |options: &RfOptions| {
+            let mut moments = crate::RfPulseMomentVec::default();
+            seq.integrate_rf(&time, options, &mut moments);
+            moments.angle[0]
+        };
+        let on_resonant = integrate(&RfOptions::default());
+        check!((on_resonant - 10f64.to_radians()).abs() < 1e-3);
+
+        // Far off-resonant spins are not excited
+        let far = integrate(&RfOptions {
+            off_resonance: 1e6,
+            ..Default::default()
+        });
+        check!(far < 1e-2);
+
+        // Off-resonance compensated by the slice gradient at the spin position
+        let compensated = integrate(&RfOptions {
+            off_resonance: -444.444,
+            position: Some((0.0, 0.0, 1e-3)),
+            ..Default::default()
+        });
+        check!((compensated - on_resonant).abs() < 1e-9);
+
+        // Outside of the slice
+        let outside = integrate(&RfOptions {
+            position: Some((0.0, 0.0, 0.01)),
+            ..Default::default()
+        });
+        check!(outside < 0.1 * on_resonant);
+    }
+
+    #[test]
+    fn pulseq_1_5() {
+        let seq = PulseqSequence::from_source(SE_1_5).unwrap();
+        check!((seq.duration() - 5.4e-3).abs() < 1e-12);
+        check!(seq.validate().is_empty());
+
+        // Usage and center are read from the file instead of being estimated
+        let pulses: Vec<_> = seq
+            .encounters(EventType::RfPulse, 0.0, f64::INFINITY)
+            .collect();
+        check!(pulses.len() == 2);
+        let excitation = seq.pulse_info(pulses[0]).unwrap();
+        check!(excitation.usage == PulseUsage::Excitation);
+        check!((excitation.center - 0.6e-3).abs() < 1e-12);
+        check!((excitation.isodelay - 0.5e-3).abs() < 1e-12);
+        let refocusing = seq.pulse_info(pulses[1]).unwrap();
+        check!(refocusing.usage == PulseUsage::Refocusing);
+        check!((refocusing.center - 3.8e-3).abs() < 1e-12);
+
+        // The 12 column RF and 9 column ADC rows are read correctly
+        let rf = seq.sample(3.8e-3).0;
+        check!(rf.amplitude == 500.0);
+        check!(rf.phase == 1.25);
+        let readout = seq.adc_readouts(0.0, f64::INFINITY).next().unwrap();
+        check!(readout.num_samples == 64);
+        check!((readout.dwell - 10e-6).abs() < 1e-15);
+        check!((readout.start - 4.425e-3).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rasters() {
+        let rasters = PulseqSequence::from_source(GRE).unwrap().rasters().unwrap();
+        check!(rasters.rf == 1e-6);
+        check!(rasters.gradient_x == 1e-5);
+        check!(rasters.adc == 1e-7);
+        check!(rasters.block == Some(1e-5));
     }
 }
@@ -0,0 +1,462 @@
+//! Parts of the .seq format that pulseq-rs doesn't expose, read directly
+//! from the source. Extensions are handled separately in `extensions.rs`.
+
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use super::helpers::ShapedGrad;
+use crate::{util::decompress_shape, Error, PulseUsage};
+
+/// Usage and center of a pulseq 1.5 RF event
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RfInfo {
+    pub usage: PulseUsage,
+    /// Center in seconds, relative to the start of the shape (excluding delay)
+    pub center: f64,
+}
+
+/// A .seq file split into its sections. It is parsed once and shared by
+/// everything that reads parts of the format pulseq-rs doesn't expose.
+#[derive(Debug, Default)]
+pub struct RawSeq<'a> {
+    source: &'a str,
+    /// Content lines of every `[SECTION]` together with their line numbers
+    /// (starting at 1). Empty lines and comments are skipped.
+    sections: HashMap<&'a str, Vec<(usize, &'a str)>>,
+    /// Rows of `[BLOCKS]` in file order
+    pub blocks: Vec<RawBlock>,
+}
+
+/// Block id and gradient channel (0 = x, 1 = y, 2 = z)
+pub type GradKey = (u32, usize);
+
+/// Event ids of a `[BLOCKS]` row, 0 if the block doesn't have the event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawBlock {
+    pub id: u32,
+    pub rf: u32,
+    /// Gradient ids of the x, y and z channel
+    pub grads: [u32; 3],
+    /// First entry of the extension list, older files don't have this column
+    pub ext: u32,
+}
+
+impl<'a> RawSeq<'a> {
+    pub fn parse(source: &'a str) -> Result<Self, Error> {
+        let mut raw = Self {
+            source,
+            ..Self::default()
+        };
+        let mut section = None;
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(raw.sections.entry(name).or_default());
+            } else if let Some(section) = &mut section {
+                section.push((i + 1, line));
+            }
+        }
+
+        raw.blocks = raw
+            .section("BLOCKS")
+            .iter()
+            .map(|&(line, content)| {
+                // NUM DUR RF GX GY GZ ADC EXT
+                let fields: Vec<u32> = content
+                    .split_whitespace()
+                    .map(|x| x.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid(line, content))?;
+                let (id, rf, grads, ext) = match fields[..] {
+                    [id, _, rf, gx, gy, gz, _] => (id, rf, [gx, gy, gz], 0),
+                    [id, _, rf, gx, gy, gz, _, ext] => (id, rf, [gx, gy, gz], ext),
+                    _ => return Err(invalid(line, content)),
+                };
+                Ok(RawBlock { id, rf, grads, ext })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(raw)
+    }
+
+    /// Content lines of the given `[SECTION]`, empty if it doesn't exist
+    pub fn section(&self, name: &str) -> &[(usize, &'a str)] {
+        self.sections.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// The source with the time shape ids of all `[GRADIENTS]` rows set to 0,
+    /// to be parsed by pulseq-rs. It would play time shaped gradients out on
+    /// the uniform raster, they are read by [`shaped_grads`] instead.
+    pub fn without_time_shapes(&self) -> Cow<'a, str> {
+        let rows: HashMap<usize, String> = self
+            .section("GRADIENTS")
+            .iter()
+            .filter_map(|&(line, content)| {
+                let mut fields: Vec<&str> = content.split_whitespace().collect();
+                let time_shape = match fields.len() {
+                    5 => 3,
+                    7 => 5,
+                    _ => return None,
+                };
+                if fields[time_shape].parse::<f64>().is_ok_and(|id| id == 0.0) {
+                    return None;
+                }
+                fields[time_shape] = "0";
+                Some((line, fields.join(" ")))
+            })
+            .collect();
+        if rows.is_empty() {
+            return Cow::Borrowed(self.source);
+        }
+
+        let lines: Vec<&str> = (self.source.lines().enumerate())
+            .map(|(i, line)| rows.get(&(i + 1)).map_or(line, String::as_str))
+            .collect();
+        Cow::Owned(lines.join("\n"))
+    }
+
+    /// Parses and decompresses all shapes of the `[SHAPES]` section, by id.
+    /// Shapes are stored as `shape_id ID`, `num_samples N` and the values,
+    /// which are compressed if there are less than N.
+    fn shapes(&self) -> Result<HashMap<u32, Vec<f64>>, Error> {
+        // (id, num_samples, values) of all shapes
+        let mut shapes: Vec<(u32, usize, Vec<f64>)> = Vec::new();
+        for &(line, content) in self.section("SHAPES") {
+            if let Some(id) = content.strip_prefix("shape_id") {
+                let id = id.trim().parse().map_err(|_| invalid(line, content))?;
+                shapes.push((id, 0, Vec::new()));
+            } else if let Some(count) = content.strip_prefix("num_samples") {
+                let shape = shapes.last_mut().ok_or_else(|| invalid(line, content))?;
+                shape.1 = count.trim().parse().map_err(|_| invalid(line, content))?;
+            } else {
+                let shape = shapes.last_mut().ok_or_else(|| invalid(line, content))?;
+                shape
+                    .2
+                    .push(content.parse().map_err(|_| invalid(line, content))?);
+            }
+        }
+
+        shapes
+            .into_iter()
+            .map(|(id, count, values)| {
+                if values.len() == count {
+                    return Ok((id, values));
+                }
+                let shape = decompress_shape(&values, count).map_err(|actual| {
+                    Error::InvalidPulseqShape {
+                        id,
+                        reason: format!("expected {count} samples, but decompressed {actual}"),
+                    }
+                })?;
+                Ok((id, shape))
+            })
+            .collect()
+    }
+}
+
+fn invalid(line: usize, content: &str) -> Error {
+    Error::InvalidPulseqLine {
+        line,
+        content: content.to_owned(),
+        reason: "could not parse",
+    }
+}
+
+/// Returns the RF usage and center of every block with an RF event. Only
+/// pulseq 1.5 stores them: RF rows with the format
+/// `id amp mag_id phase_id time_id center delay freq_ppm phase_ppm freq phase use`,
+/// where center is in microseconds. The map is empty for older files.
+pub fn rf_info(raw: &RawSeq) -> Result<HashMap<u32, RfInfo>, Error> {
+    let mut rfs = HashMap::new();
+    for &(line, content) in raw.section("RF") {
+        let fields: Vec<&str> = content.split_whitespace().collect();
+        // Older versions have less columns and no usage
+        let &[id, _, _, _, time_shape, center, _, _, _, _, _, usage] = fields.as_slice() else {
+            continue;
+        };
+        // Negative ids select the default oversampled time shape
+        if time_shape.starts_with('-') {
+            return Err(Error::InvalidPulseqLine {
+                line,
+                content: content.to_owned(),
+                reason: "oversampled time shapes are not supported",
+            });
+        }
+        let id: u32 = id.parse().map_err(|_| invalid(line, content))?;
+        let center: f64 = center.parse().map_err(|_| invalid(line, content))?;
+        let usage = match usage {
+            "e" => PulseUsage::Excitation,
+            "r" => PulseUsage::Refocusing,
+            "i" => PulseUsage::Inversion,
+            "s" => PulseUsage::Saturation,
+            "p" => PulseUsage::Preparation,
+            "o" => PulseUsage::Other,
+            _ => PulseUsage::Undefined,
+        };
+        rfs.insert(
+            id,
+            RfInfo {
+                usage,
+                center: center * 1e-6,
+            },
+        );
+    }
+
+    Ok(raw
+        .blocks
+        .iter()
+        .filter_map(|block| Some((block.id, *rfs.get(&block.rf)?)))
+        .collect())
+}
+
+/// Returns the free gradients with a time shape of every block, by block id
+/// and channel. GRADIENTS rows have the format
+/// `id amp amp_shape_id time_shape_id delay` (1.4) or
+/// `id amp first last amp_shape_id time_shape_id delay` (1.5), the time
+/// shape is in units of the gradient raster. Rows without a time shape are
+/// left to pulseq-rs.
+pub fn shaped_grads(
+    raw: &RawSeq,
+    grad_raster: f64,
+) -> Result<HashMap<GradKey, Arc<ShapedGrad>>, Error> {
+    // Gradient id -> (line, content, amp, amp shape id, time shape id, delay)
+    let mut grads = HashMap::new();
+    for &(line, content) in raw.section("GRADIENTS") {
+        let fields: Vec<f64> = content
+            .split_whitespace()
+            .map(|x| x.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid(line, content))?;
+        let (id, amp, amp_shape, time_shape, delay) = match fields[..] {
+            [id, amp, amp_shape, time_shape, delay] => (id, amp, amp_shape, time_shape, delay),
+            [id, amp, _, _, amp_shape, time_shape, delay] => {
+                (id, amp, amp_shape, time_shape, delay)
+            }
+            _ => return Err(invalid(line, content)),
+        };
+        // Negative ids select the default oversampled time shape of pulseq 1.5
+        if time_shape < 0.0 {
+            return Err(Error::InvalidPulseqLine {
+                line,
+                content: content.to_owned(),
+                reason: "oversampled time shapes are not supported",
+            });
+        }
+        if time_shape > 0.0 {
+            let shape_ids = (amp_shape as u32, time_shape as u32);
+            grads.insert(id as u32, (line, content, amp, shape_ids, delay));
+        }
+    }
+    if grads.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let shapes = raw.shapes()?;
+    let mut shaped = HashMap::new();
+    for (id, (line, content, amp, (amp_shape, time_shape), delay)) in grads {
+        let error = |reason| Error::InvalidPulseqLine {
+            line,
+            content: content.to_owned(),
+            reason,
+        };
+        let (Some(amps), Some(times)) = (shapes.get(&amp_shape), shapes.get(&time_shape)) else {
+            return Err(error("missing amplitude or time shape"));
+        };
+        let grad = ShapedGrad {
+            delay: delay * 1e-6,
+            times: times.iter().map(|t| t * grad_raster).collect(),
+            amps: amps.iter().map(|x| x * amp).collect(),
+        };
+        if grad.times.len() != grad.amps.len() || grad.times.windows(2).any(|t| t[0] > t[1]) {
+            return Err(error("time shape doesn't fit to the amplitudes"));
+        }
+        shaped.insert(id, Arc::new(grad));
+    }
+
+    let mut blocks = HashMap::new();
+    for block in &raw.blocks {
+        for (channel, grad) in block.grads.iter().enumerate() {
+            if let Some(grad) = shaped.get(grad) {
+                blocks.insert((block.id, channel), grad.clone());
+            }
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Returns the stored start and end amplitude in Hz/m of the free gradients
+/// of every block, by block id and channel. Only pulseq 1.5 stores them:
+/// GRADIENTS rows with the format
+/// `id amp first last amp_shape_id time_shape_id delay`. The map is empty for
+/// older files, which extrapolate them from the shape instead.
+pub fn grad_first_last(raw: &RawSeq) -> Result<HashMap<GradKey, (f64, f64)>, Error> {
+    let mut grads = HashMap::new();
+    for &(line, content) in raw.section("GRADIENTS") {
+        let fields: Vec<&str> = content.split_whitespace().collect();
+        let &[id, _, first, last, _, _, _] = fields.as_slice() else {
+            continue;
+        };
+        let id: u32 = id.parse().map_err(|_| invalid(line, content))?;
+        let first: f64 = first.parse().map_err(|_| invalid(line, content))?;
+        let last: f64 = last.parse().map_err(|_| invalid(line, content))?;
+        grads.insert(id, (first, last));
+    }
+
+    let mut blocks = HashMap::new();
+    for block in &raw.blocks {
+        for (channel, grad) in block.grads.iter().enumerate() {
+            if let Some(&first_last) = grads.get(grad) {
+                blocks.insert((block.id, channel), first_last);
+            }
+        }
+    }
+
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{grad_first_last, rf_info, shaped_grads, RawSeq, RfInfo};
+    use crate::{Error, PulseUsage};
+    use assert2::check;
+
+    #[test]
+    fn rf_usage() {
+        let source = "\
+[BLOCKS]
+1 100 1 0 0 0 0 0
+2 100 0 0 0 0 1 0
+3 100 2 0 0 0 0 0
+
+[RF]
+# id amp mag_id phase_id time_id center delay freqPPM phasePPM freq phase use
+1 250 1 2 0 1000 100 0 0 0 0 e
+2 500 1 2 0 1500 0 0 0 0 0 r
+";
+        let info = rf_info(&RawSeq::parse(source).unwrap()).unwrap();
+        check!(info.len() == 2);
+        check!(
+            info[&1]
+                == RfInfo {
+                    usage: PulseUsage::Excitation,
+                    center: 1e-3
+                }
+        );
+        check!(info[&3].usage == PulseUsage::Refocusing);
+
+        // Pulseq 1.4 files don't store usage and center
+        let gre = include_str!("../../examples/gre.seq");
+        check!(rf_info(&RawSeq::parse(gre).unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn shapes() {
+        let source = "\
+[SHAPES]
+
+shape_id 1
+num_samples 7
+0
+0.1
+0.1
+3
+0
+
+shape_id 2
+num_samples 2
+1
+2
+";
+        // 0, 0.1, ..., 0.5, 0.5: derivative 0 0.1 0.1 0.1 0.1 0.1 0
+        let shapes = RawSeq::parse(source).unwrap().shapes().unwrap();
+        let expected = [0.0, 0.1, 0.2, 0.3, 0.4, 0.5, 0.5];
+        check!(shapes[&1].len() == expected.len());
+        for (x, expected) in shapes[&1].iter().zip(expected) {
+            check!((x - expected).abs() < 1e-12);
+        }
+        // Uncompressed
+        check!(shapes[&2] == [1.0, 2.0]);
+
+        let source = source.replace("num_samples 7", "num_samples 4");
+        let shapes = RawSeq::parse(&source).unwrap().shapes();
+        check!(let Err(Error::InvalidPulseqShape { id: 1, .. }) = shapes);
+    }
+
+    #[test]
+    fn time_shapes() {
+        let source = "\
+[BLOCKS]
+1 100 0 1 0 2 0 0
+2 100 0 2 0 0 0 0
+
+[GRADIENTS]
+1 1000 1 2 10
+2 500 1 0 0
+
+[SHAPES]
+
+shape_id 1
+num_samples 3
+0
+1
+0
+
+shape_id 2
+num_samples 3
+0
+10
+30
+";
+        let grads = shaped_grads(&RawSeq::parse(source).unwrap(), 1e-5).unwrap();
+        // Gradient 2 has no time shape and is left to pulseq-rs
+        check!(grads.len() == 1);
+        let grad = &grads[&(1, 0)];
+        check!((grad.delay - 10e-6).abs() < 1e-15);
+        check!(grad.amps == [0.0, 1000.0, 0.0]);
+        check!((grad.times[2] - 3e-4).abs() < 1e-15);
+
+        // pulseq-rs only sees the gradients without time shape
+        let without = RawSeq::parse(source).unwrap().without_time_shapes();
+        check!(without.contains("\n1 1000 1 0 10\n2 500 1 0 0\n"));
+
+        // Errors point to the GRADIENTS row
+        let missing = source.replacen("1 1000 1 2 10", "1 1000 1 3 10", 1);
+        let result = shaped_grads(&RawSeq::parse(&missing).unwrap(), 1e-5);
+        check!(let Err(Error::InvalidPulseqLine { line: 6, .. }) = result);
+        let oversampled = source.replacen("1 1000 1 2 10", "1 1000 1 -1 10", 1);
+        let result = shaped_grads(&RawSeq::parse(&oversampled).unwrap(), 1e-5);
+        check!(let Err(Error::InvalidPulseqLine { line: 6, .. }) = result);
+    }
+
+    #[test]
+    fn first_last() {
+        let source = "\
+[BLOCKS]
+1 100 0 1 0 2 0 0
+2 100 0 0 2 0 0 0
+
+[GRADIENTS]
+# id amp first last amp_shape_id time_shape_id delay
+1 1000 0 500 1 0 0
+2 -500 -250 0 1 0 10
+";
+        let first_last = grad_first_last(&RawSeq::parse(source).unwrap()).unwrap();
+        check!(first_last.len() == 3);
+        check!(first_last[&(1, 0)] == (0.0, 500.0));
+        check!(first_last[&(1, 2)] == (-250.0, 0.0));
+        check!(first_last[&(2, 1)] == (-250.0, 0.0));
+
+        // Pulseq 1.4 files don't store first and last
+        let kspace = include_str!("../../examples/kspace.seq");
+        check!(grad_first_last(&RawSeq::parse(kspace).unwrap())
+            .unwrap()
+            .is_empty());
+
+        let invalid = source.replacen("1 1000 0 500", "1 1000 0 x", 1);
+        let result = grad_first_last(&RawSeq::parse(&invalid).unwrap());
+        check!(let Err(Error::InvalidPulseqLine { line: 7, .. }) = result);
+    }
+}
@@ -0,0 +1,285 @@
+//! Timing and consistency checks, similar to the ones pulseq's MATLAB
+//! `checkTiming` does before a sequence is run on the scanner.
+
+use pulseq_rs::Gradient;
+
+use super::{helpers::Grad, PulseqSequence};
+use crate::{EventType, GradientChannel, ValidationIssue};
+
+/// Times are compared with this tolerance in seconds, which is far below
+/// every raster but above the rounding errors of the µs -> s conversion.
+pub const TIME_TOLERANCE: f64 = 1e-9;
+
+/// Gradient jumps smaller than this fraction of the largest gradient
+/// amplitude of the sequence are not reported.
+const DISCONTINUITY_TOLERANCE: f64 = 1e-3;
+
+pub fn validate(seq: &PulseqSequence) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    let raster = &seq.raster;
+
+    for (block_idx, (_, block)) in seq.blocks.iter().enumerate() {
+        if !on_raster(block.duration, raster.block) {
+            issues.push(ValidationIssue::BlockDurationOffRaster {
+                block: block.id,
+                duration: block.duration,
+                raster: raster.block,
+            });
+        }
+
+        for event in EventType::ALL {
+            let Some((_, end)) = seq.block_encounter(block_idx, event) else {
+                continue;
+            };
+            if end > block.duration + TIME_TOLERANCE {
+                issues.push(ValidationIssue::EventExceedsBlock {
+                    block: block.id,
+                    event,
+                    end,
+                    duration: block.duration,
+                });
+            }
+        }
+
+        // Like pulseq, ADC delays must be on the RF raster
+        let [gx, gy, gz] = &seq.grads[block_idx];
+        let delays = [
+            (
+                EventType::RfPulse,
+                block.rf.as_ref().map(|rf| rf.delay),
+                raster.rf,
+            ),
+            (
+                EventType::Adc,
+                block.adc.as_ref().map(|adc| adc.delay),
+                raster.rf,
+            ),
+            (
+                EventType::Gradient(GradientChannel::X),
+                gx.as_ref().map(|gx| gx.delay()),
+                raster.grad,
+            ),
+            (
+                EventType::Gradient(GradientChannel::Y),
+                gy.as_ref().map(|gy| gy.delay()),
+                raster.grad,
+            ),
+            (
+                EventType::Gradient(GradientChannel::Z),
+                gz.as_ref().map(|gz| gz.delay()),
+                raster.grad,
+            ),
+        ];
+        for (event, delay, raster) in delays {
+            if let Some(delay) = delay.filter(|&delay| !on_raster(delay, raster)) {
+                issues.push(ValidationIssue::DelayOffRaster {
+                    block: block.id,
+                    event,
+                    delay,
+                    raster,
+                });
+            }
+        }
+    }
+
+    check_gradient_continuity(seq, &mut issues);
+
+    let total_duration = seq.definitions.get("TotalDuration");
+    if let Some(definition) = total_duration.and_then(|s| s.trim().parse::<f64>().ok()) {
+        // Soft delay values change the duration, but not the definition
+        let actual = seq.file_duration;
+        if (definition - actual).abs() > 0.5 * raster.block {
+            issues.push(ValidationIssue::TotalDuration { definition, actual });
+        }
+    }
+
+    issues
+}
+
+fn check_gradient_continuity(seq: &PulseqSequence, issues: &mut Vec<ValidationIssue>) {
+    let max_amp = seq
+        .grads
+        .iter()
+        .flatten()
+        .flatten()
+        .map(Grad::max_amp)
+        .fold(0.0, f64::max);
+    let tolerance = DISCONTINUITY_TOLERANCE * max_amp;
+
+    let raster = seq.raster.grad;
+    let mut before = [0.0; 3];
+    let mut time = 0.0;
+    for (block_idx, (block_start, block)) in seq.blocks.iter().enumerate() {
+        let after = seq.physical_grad(block_idx, |grad| grad_edges(grad, block.duration, raster).0);
+        report_jumps(time, before, after, tolerance, issues);
+
+        before = seq.physical_grad(block_idx, |grad| grad_edges(grad, block.duration, raster).1);
+        time = block_start + block.duration;
+    }
+    report_jumps(time, before, [0.0; 3], tolerance, issues);
+}
+
+fn report_jumps(
+    time: f64,
+    before: [f64; 3],
+    after: [f64; 3],
+    tolerance: f64,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    let channels = [GradientChannel::X, GradientChannel::Y, GradientChannel::Z];
+    for ((channel, before), after) in channels.into_iter().zip(before).zip(after) {
+        if (before - after).abs() > tolerance {
+            issues.push(ValidationIssue::GradientDiscontinuity {
+                time,
+                channel,
+                before,
+                after,
+            });
+        }
+    }
+}
+
+/// Gradient amplitude at the start and end of the block. Free gradients are
+/// sampled in the raster centers, their edges are stored (pulseq 1.5) or
+/// extrapolated like for the integration. Gradients with a time shape are
+/// exact at their first and last vertex.
+fn grad_edges(grad: &Grad, block_duration: f64, raster: f64) -> (f64, f64) {
+    let (grad, (first, last)) = match grad {
+        Grad::Pulseq { grad, edges } => (grad.as_ref(), *edges),
+        Grad::Shaped(grad) => {
+            let (Some(&first), Some(&last)) = (grad.amps.first(), grad.amps.last()) else {
+                return (0.0, 0.0);
+            };
+            let start = grad.delay + grad.times[0];
+            let end = grad.delay + grad.times[grad.times.len() - 1];
+            return (
+                if start < TIME_TOLERANCE { first } else { 0.0 },
+                if end > block_duration - TIME_TOLERANCE {
+                    last
+                } else {
+                    0.0
+                },
+            );
+        }
+    };
+    match grad {
+        Gradient::Free { amp, delay, shape } => {
+            let starts_at_block = *delay < TIME_TOLERANCE;
+            let ends_at_block =
+                delay + shape.0.len() as f64 * raster > block_duration - TIME_TOLERANCE;
+            (
+                if starts_at_block { amp * first } else { 0.0 },
+                if ends_at_block { amp * last } else { 0.0 },
+            )
+        }
+        Gradient::Trap { .. } => (first, last),
+    }
+}
+
+fn on_raster(t: f64, raster: f64) -> bool {
+    let steps = t / raster;
+    (steps - steps.round()).abs() * raster < TIME_TOLERANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PulseqSequence;
+    use crate::{Backend, EventType, GradientChannel, LoadOptions, ValidationIssue};
+    use assert2::check;
+    use std::collections::HashMap;
+
+    const GRE: &str = include_str!("../../examples/gre.seq");
+
+    fn validate(source: &str) -> Vec<ValidationIssue> {
+        PulseqSequence::from_source(source).unwrap().validate()
+    }
+
+    #[test]
+    fn valid() {
+        check!(validate(GRE).is_empty());
+    }
+
+    #[test]
+    fn soft_delays() {
+        // A longer TE fill delay (block 3) doesn't contradict TotalDuration
+        let source = GRE.replacen(
+            "   3  78   0   0   0   0  0  0",
+            "   3  78   0   0   0   0  0  1",
+            1,
+        ) + "\n[EXTENSIONS]\n1 1 1 0\n\nextension DELAYS 1\n1 1 0 1 TE\n";
+        let options = LoadOptions {
+            soft_delays: HashMap::from([("TE".to_owned(), 2e-3)]),
+            ..Default::default()
+        };
+        let seq = PulseqSequence::from_source_with_options(&source, &options).unwrap();
+        check!(seq.duration() > 3.072 + 1e-3);
+        check!(seq.validate().is_empty());
+    }
+
+    #[test]
+    fn timing() {
+        // Shorten the first block, so that the slice selection and RF pulse exceed it
+        let source = GRE
+            .replacen("   1 317   1", "   1 300   1", 1)
+            .replacen("TotalDuration 3.072", "TotalDuration 3.1", 1)
+            .replacen(
+                "1      37.2185 1 2 0 100 0 0",
+                "1      37.2185 1 2 0 100.5 0 0",
+                1,
+            );
+        let issues = validate(&source);
+
+        let exceeding: Vec<_> = issues
+            .iter()
+            .filter_map(|issue| match issue {
+                ValidationIssue::EventExceedsBlock {
+                    block: 1, event, ..
+                } => Some(*event),
+                _ => None,
+            })
+            .collect();
+        check!(exceeding == [EventType::RfPulse, EventType::Gradient(GradientChannel::Z)]);
+        check!(issues.iter().any(|issue| matches!(
+            issue,
+            ValidationIssue::DelayOffRaster {
+                event: EventType::RfPulse,
+                ..
+            }
+        )));
+        check!(issues.iter().any(|issue| matches!(
+            issue,
+            ValidationIssue::TotalDuration { definition, .. } if *definition == 3.1
+        )));
+    }
+
+    #[test]
+    fn gradient_discontinuity() {
+        // 20 us constant gradient at the start of the TE delay block (block 3)
+        let source = GRE
+            .replacen(
+                "   3  78   0   0   0   0  0  0",
+                "   3  78   0 999   0   0  0  0",
+                1,
+            )
+            .replacen(
+                "\n[SIGNATURE]",
+                "\nshape_id 999\nnum_samples 2\n1\n1\n\n[GRADIENTS]\n999 100000 999 0 0\n\n[SIGNATURE]",
+                1,
+            );
+        let issues = validate(&source);
+
+        check!(issues.len() == 1);
+        let ValidationIssue::GradientDiscontinuity {
+            time,
+            channel: GradientChannel::X,
+            before,
+            after,
+        } = issues[0]
+        else {
+            panic!("unexpected issue: {:?}", issues[0]);
+        };
+        check!((time - 4.17e-3).abs() < 1e-12);
+        check!(before == 0.0);
+        check!(after == 100000.0);
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Error type returned by all disseqt loaders. Loading never panics on
+/// malformed input; every problem found in the files is reported here.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The pulseq file could not be parsed
+    #[error(transparent)]
+    Pulseq(#[from] pulseq_rs::Error),
+    /// A required file does not exist or could not be read
+    #[error("File not found: {}", .0.display())]
+    FileNotFound(PathBuf),
+    /// The in-memory DSV sequence doesn't contain the given channel
+    #[error("Missing DSV channel {0:?}")]
+    MissingChannel(String),
+    /// Reading the sequence from a reader failed
+    #[error("Could not read sequence: {0}")]
+    Io(#[from] std::io::Error),
+    /// The path neither points to a pulseq nor to a DSV sequence
+    #[error("Could not detect the sequence format of {}", .0.display())]
+    UnknownFormat(PathBuf),
+    /// The directory contains more than one sequence
+    #[error("{} contains multiple sequences, please specify the file", .0.display())]
+    AmbiguousFormat(PathBuf),
+    /// A part of a pulseq file that pulseq-rs doesn't parse itself is invalid
+    /// or not supported (line numbers start at 1)
+    #[error("pulseq file, line {line}: {reason}: {content:?}")]
+    InvalidPulseqLine {
+        line: usize,
+        content: String,
+        reason: &'static str,
+    },
+    /// A shape of a pulseq file that pulseq-rs doesn't decompress itself is invalid
+    #[error("pulseq shape {id}: {reason}")]
+    InvalidPulseqShape { id: u32, reason: String },
+    /// The extensions of a pulseq file could not be parsed (line numbers start at 1)
+    #[error("pulseq extensions, line {line}: could not parse {content:?}")]
+    InvalidExtension { line: usize, content: String },
+    /// The load options set a soft delay that is not defined by the sequence
+    #[error("unknown soft delay {0:?}")]
+    UnknownSoftDelay(String),
+    /// The soft delay value results in a block duration that is negative or
+    /// shorter than the events of the block
+    #[error("soft delay {hint} = {value} s results in a block that is shorter than its events")]
+    InvalidSoftDelay { hint: String, value: f64 },
+    /// Blocks that share a soft delay hint have different offsets or factors
+    #[error("soft delay {0:?} is defined with different offsets or factors")]
+    InconsistentSoftDelay(String),
+    /// A required `[SECTION]` is missing in the file
+    #[error("{file}: missing [{section}] section")]
+    MissingSection { file: String, section: String },
+    /// A required definition is missing in the file
+    #[error("{file}: missing definition {key:?}")]
+    MissingKey { file: String, key: String },
+    /// A definition exists but its value could not be parsed
+    #[error("{file}: could not parse definition {key} = {value:?}")]
+    InvalidValue {
+        file: String,
+        key: String,
+        value: String,
+    },
+    /// A line of the file could not be parsed (line numbers start at 1)
+    #[error("{file}, line {line}: could not parse {content:?}")]
+    InvalidLine {
+        file: String,
+        line: usize,
+        content: String,
+    },
+    /// The unit of a time or amplitude axis is not supported
+    #[error("{file}: unknown unit {unit:?}")]
+    UnknownUnit { file: String, unit: String },
+    /// Amplitudes are given in Volt, which requires a reference voltage
+    #[error("{file}: amplitude unit is Volt, but no reference voltage was provided")]
+    MissingRefVoltage { file: String },
+    /// The decompressed shape has a different length than announced in the file
+    #[error("{file}: expected {expected} samples, but decompressed {actual}")]
+    SampleCount {
+        file: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// Two files that describe the same channel don't fit together
+    #[error("{file_a} and {file_b} disagree in {property}")]
+    ChannelMismatch {
+        file_a: String,
+        file_b: String,
+        property: &'static str,
+    },
+    /// The channel is too short to detect events (pulses, ADC blocks, ...) in it
+    #[error("{file}: {count} samples are not enough to detect events (need more than {min})")]
+    TooFewSamples {
+        file: String,
+        count: usize,
+        min: usize,
+    },
+    /// The channel could not be split into separate events
+    #[error("{file}: could not separate the samples into events")]
+    InvalidEvents { file: String },
+}
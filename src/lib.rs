@@ -1,102 +1,572 @@
+mod backend;
 mod backend_dsv;
 mod backend_pulseq;
+mod error;
+mod load;
 mod types;
 mod util;
 
-use std::path::Path;
+pub use backend::Backend;
+pub use error::Error;
+pub use load::{load, LoadOptions};
+use std::{collections::HashMap, io::Read, path::Path, sync::Arc};
 pub use types::*;
-pub use pulseq_rs::Error;
 
-pub fn load_pulseq<P: AsRef<Path>>(path: P) -> Result<Sequence, pulseq_rs::Error> {
-    Ok(Sequence(Box::new(backend_pulseq::PulseqSequence::load(
+pub fn load_pulseq<P: AsRef<Path>>(path: P) -> Result<Sequence, Error> {
+    Ok(Sequence(Arc::new(backend_pulseq::PulseqSequence::load(
         path,
+        &LoadOptions::default(),
     )?)))
 }
 
+/// Load a pulseq sequence from the contents of a .seq file
+pub fn load_pulseq_from_str(source: &str) -> Result<Sequence, Error> {
+    Ok(Sequence(Arc::new(
+        backend_pulseq::PulseqSequence::from_source(source)?,
+    )))
+}
+
+/// Load a pulseq sequence by reading a .seq file from the given reader
+pub fn load_pulseq_from_reader<R: Read>(mut reader: R) -> Result<Sequence, Error> {
+    let mut source = String::new();
+    reader.read_to_string(&mut source)?;
+    load_pulseq_from_str(&source)
+}
+
 pub fn load_dsv<P: AsRef<Path>>(
     path: P,
     resolution: Option<usize>,
     ref_voltage: f64,
-) -> Result<Sequence, backend_dsv::Error> {
-    Ok(Sequence(Box::new(backend_dsv::DsvSequence::load(
+) -> Result<Sequence, Error> {
+    Ok(Sequence(Arc::new(backend_dsv::DsvSequence::load(
         path,
         resolution,
-        ref_voltage,
+        Some(ref_voltage),
+        Interpolation::default(),
+    )?)))
+}
+
+/// Load a DSV sequence from the contents of its files. The map is indexed by
+/// the channel name, which is the `XXX` in `<stem>_XXX.dsv` (`"RFD"`, `"RFP"`,
+/// `"GRX"`, `"GRY"`, `"GRZ"`, `"ADC"`, `"NC1"`). `"RFP"` is optional.
+pub fn load_dsv_from_buffers(
+    buffers: &HashMap<String, Vec<u8>>,
+    resolution: Option<usize>,
+    ref_voltage: f64,
+) -> Result<Sequence, Error> {
+    Ok(Sequence(Arc::new(backend_dsv::DsvSequence::from_buffers(
+        buffers,
+        resolution,
+        Some(ref_voltage),
+        Interpolation::default(),
     )?)))
 }
 
 /// A disseqt sequence. This opaque type on purpose does not expose the sequence data,
 /// but provides a simple interface which makes it possible to build importers and more
 /// that efficiently work with all supported MRI file formats.
-pub struct Sequence(pub(crate) Box<dyn Backend>);
+/// Cloning is cheap (the loaded sequence is shared) and sequences can be used
+/// from multiple threads at once.
+#[derive(Clone)]
+pub struct Sequence(pub(crate) Arc<dyn Backend>);
 
 // Largely just forwards the trait impls, but also adds convenicence functions.
 impl Sequence {
+    /// Wrap a custom backend, e.g. for a file format not supported by disseqt.
+    pub fn from_backend<B: Backend + 'static>(backend: B) -> Self {
+        Self(Arc::new(backend))
+    }
+
     pub fn fov(&self) -> Option<(f64, f64, f64)> {
         self.0.fov()
     }
 
+    pub fn metadata(&self) -> Metadata {
+        self.0.metadata()
+    }
+
+    /// Native time resolution of all channels, e.g. to sample exactly on the
+    /// grid of the source data. `None` if the format has no fixed rasters.
+    pub fn rasters(&self) -> Option<Rasters> {
+        self.0.rasters()
+    }
+
     pub fn duration(&self) -> f64 {
         self.0.duration()
     }
+
+    /// Returns the soft delays (e.g. TE or TR) of the sequence, which can be
+    /// changed with [`LoadOptions::soft_delays`]. Empty for formats without them.
+    pub fn soft_delays(&self) -> Vec<SoftDelay> {
+        self.0.soft_delays()
+    }
+
     /// TODO: EventType should be the first parameter
     pub fn encounter(&self, t_start: f64, ty: EventType) -> Option<(f64, f64)> {
         self.0.encounter(t_start, ty)
     }
 
+    /// Lazily iterates over all encounters of the given type that start in the
+    /// time range [t_start, t_end), ordered by their start time.
+    pub fn encounters(
+        &self,
+        ty: EventType,
+        t_start: f64,
+        t_end: f64,
+    ) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.0.encounters(ty, t_start, t_end)
+    }
+
     pub fn events(&self, ty: EventType, t_start: f64, t_end: f64, max_count: usize) -> Vec<f64> {
         self.0.events(ty, t_start, t_end, max_count)
     }
+
+    /// Lazily iterates over all events of the given type in the time range
+    /// [t_start, t_end). Every event is returned exactly once, in strictly
+    /// increasing order, so there is no need to advance time manually.
+    pub fn events_iter(
+        &self,
+        ty: EventType,
+        t_start: f64,
+        t_end: f64,
+    ) -> impl Iterator<Item = f64> + '_ {
+        self.0.events_iter(ty, t_start, t_end)
+    }
+    /// Returns the sorted union of the events of all given types in the time
+    /// range [t_start, t_end). Events of different types that happen at the
+    /// same time are merged into one, tagged with all types that produced it.
+    pub fn events_multi(
+        &self,
+        types: &[EventType],
+        t_start: f64,
+        t_end: f64,
+    ) -> Vec<(f64, EventTypes)> {
+        // Backends calculate event times differently (e.g. delay + i * raster
+        // per channel), so identical times might differ by rounding errors.
+        // This is far below any raster time used by MRI sequences.
+        const MERGE_TOLERANCE: f64 = 1e-10;
+
+        let mut iters: Vec<_> = types
+            .iter()
+            .map(|&ty| (ty, self.events_iter(ty, t_start, t_end).peekable()))
+            .collect();
+        let mut events = Vec::new();
+
+        // Merge the sorted iterators by always taking the earliest next event
+        while let Some(t) = iters
+            .iter_mut()
+            .filter_map(|(_, iter)| iter.peek().copied())
+            .min_by(f64::total_cmp)
+        {
+            let mut tags = EventTypes::default();
+            for (ty, iter) in &mut iters {
+                if iter
+                    .next_if(|&t_next| t_next - t <= MERGE_TOLERANCE)
+                    .is_some()
+                {
+                    tags.insert(*ty);
+                }
+            }
+            events.push((t, tags));
+        }
+
+        events
+    }
+
+    /// Returns the labels (LIN, PAR, SLC, ...) that are active at time `t`.
+    /// Pass the start of an ADC encounter to get the labels of that readout.
+    /// Returns `None` if the sequence format doesn't support labels.
+    pub fn labels_at(&self, t: f64) -> Option<Labels> {
+        self.0.labels_at(t)
+    }
+
+    /// Iterates over all ADC encounters starting in [t_start, t_end) together
+    /// with their labels. Empty if the sequence format doesn't support labels.
+    pub fn adc_labels(
+        &self,
+        t_start: f64,
+        t_end: f64,
+    ) -> impl Iterator<Item = ((f64, f64), Labels)> + '_ {
+        self.encounters(EventType::Adc, t_start, t_end)
+            .filter_map(|adc| Some((adc, self.labels_at(adc.0)?)))
+    }
+
+    /// Returns the raw extensions (including custom ones) attached to the
+    /// blocks that start in [t_start, t_end). Empty for formats without extensions.
+    pub fn extensions(&self, t_start: f64, t_end: f64) -> Vec<ExtensionEvent> {
+        self.0.extensions(t_start, t_end)
+    }
+
+    /// Returns the ADC readouts (number of samples, dwell time, ...) whose
+    /// encounters start in [t_start, t_end), ordered by time.
+    pub fn adc_readouts(&self, t_start: f64, t_end: f64) -> impl Iterator<Item = AdcReadout> + '_ {
+        self.0.adc_readouts(t_start, t_end)
+    }
+
+    /// Maps an ADC sample time, e.g. from `events(EventType::Adc, ..)`, back
+    /// to its readout and the index of the sample in it. Returns `None` if
+    /// there is no ADC sample within half a dwell time of `t`.
+    pub fn adc_sample_at(&self, t: f64) -> Option<(AdcReadout, usize)> {
+        let readout = self.0.adc_readout_at(t)?;
+        Some((readout, readout.sample_index(t)?))
+    }
+
+    /// Returns usage, center and isodelay of the RF pulse with the given
+    /// encounter, e.g. from `encounter(t, EventType::RfPulse)`. Formats that
+    /// don't store them get an estimate: the center is the amplitude peak
+    /// (the middle of it, if there are several samples at the maximum) and
+    /// pulses with flip angles below 100° are assumed to be excitations,
+    /// all others refocusing pulses.
+    pub fn pulse_info(&self, pulse: (f64, f64)) -> PulseInfo {
+        if let Some(info) = self.0.pulse_info(pulse) {
+            return info;
+        }
+
+        let dt = self.rasters().map_or(1e-6, |rasters| rasters.rf);
+        let count = ((pulse.1 - pulse.0) / dt).round().max(1.0) as usize;
+        let time: Vec<f64> = (0..count)
+            .map(|i| pulse.0 + (i as f64 + 0.5) * dt)
+            .collect();
+        let amplitude = self.sample(&time).pulse.amplitude;
+
+        // Same tolerance for the peak as pulseq's calc_rf_center
+        let max = amplitude.iter().fold(0.0, |max: f64, x| max.max(x.abs()));
+        let mut peaks = (0..count).filter(|&i| amplitude[i].abs() >= max * 0.99999);
+        let first = peaks.next().unwrap_or(0);
+        let last = peaks.next_back().unwrap_or(first);
+        let center = 0.5 * (time[first] + time[last]);
+
+        let angle = self.integrate_one(pulse.0, pulse.1).pulse.angle;
+        PulseInfo {
+            usage: if angle.abs() < 100f64.to_radians() {
+                PulseUsage::Excitation
+            } else {
+                PulseUsage::Refocusing
+            },
+            center,
+            isodelay: pulse.1 - center,
+            estimated: true,
+        }
+    }
+
+    /// Checks the sequence for timing problems (events off raster, events
+    /// longer than their block, gradient jumps, ...) that would stop it from
+    /// running on a scanner. Returns an empty list if no problems were found.
+    /// Only pulseq sequences are checked.
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        self.0.validate()
+    }
+
     /// TODO: EventType should be the first parameter
     pub fn next_event(&self, t_start: f64, ty: EventType) -> Option<f64> {
         self.events(ty, t_start, f64::INFINITY, 1).last().cloned()
     }
 
+    /// Samples the sequence at the given time points. Shapes are evaluated
+    /// with [`LoadOptions::interpolation`], which defaults to
+    /// [`Interpolation::Nearest`]: sample `i` holds for its whole raster cell
+    /// `[i * dt, (i + 1) * dt)`. Earlier versions placed samples at the start
+    /// of their cell instead (`ceil(x - 0.5)` for pulseq free gradients,
+    /// `round(x)` for DSV), so sampled shapes are now half a raster later.
+    ///
+    /// `integrate` uses the `Linear` shape for gradients, load the sequence
+    /// with [`Interpolation::Linear`] to sample the same waveform.
     pub fn sample(&self, time: &[f64]) -> SampleVec {
-        // TODO: We do a AoS -> SoA conversion here, which should be moved into the backend
-        // so the data can be emitted directly in the desired format
-        self.0.sample(time).into()
+        let mut samples = SampleVec::default();
+        self.sample_into(time, &mut samples);
+        samples
+    }
+
+    /// Same as `sample`, but writes into an existing buffer so its
+    /// allocations can be reused over many calls. `samples` is cleared first.
+    pub fn sample_into(&self, time: &[f64], samples: &mut SampleVec) {
+        samples.clear();
+        self.0.sample(time, samples);
+        // Backends don't have to sample triggers, they are inactive then
+        while samples.trigger.active.len() < samples.pulse.len() {
+            samples.trigger.push(TriggerSample::default());
+        }
     }
 
     pub fn sample_one(&self, t: f64) -> Sample {
-        self.0.sample(&[t])[0].clone()
+        self.sample(&[t]).get(0).unwrap()
     }
 
     pub fn integrate(&self, time: &[f64]) -> MomentVec {
-        // TODO: We do a AoS -> SoA conversion here, which should be moved into the backend
-        // so the data can be emitted directly in the desired format
-        self.0.integrate(time).into()
+        let mut moments = MomentVec::default();
+        self.0.integrate(time, &mut moments);
+        moments
+    }
+
+    /// Same as `integrate`, but writes into an existing buffer so its
+    /// allocations can be reused over many calls. `moments` is cleared first.
+    pub fn integrate_into(&self, time: &[f64], moments: &mut MomentVec) {
+        moments.clear();
+        self.0.integrate(time, moments);
+    }
+
+    /// Integrates the RF pulses over the n-1 time intervalls given by the list
+    /// of n time points, taking frequency offsets and off-resonance into
+    /// account as specified by the options.
+    pub fn integrate_rf(&self, time: &[f64], options: &RfOptions) -> RfPulseMomentVec {
+        let mut moments = RfPulseMomentVec::default();
+        self.0.integrate_rf(time, options, &mut moments);
+        moments
     }
 
     pub fn integrate_one(&self, t_start: f64, t_end: f64) -> Moment {
-        self.0.integrate(&[t_start, t_end])[0]
+        self.integrate(&[t_start, t_end]).get(0).unwrap()
     }
 }
 
-/// This trait is implemented by all backends and provides the basic functions
-/// on which the public disseqt API is built upon
-trait Backend: Send {
-    /// Return the FOV of the Sequence, if it is available
-    fn fov(&self) -> Option<(f64, f64, f64)>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert2::check;
 
-    /// Duration of the MRI sequence: no samples, blocks, etc. exist outside
-    /// of the time range [0, duration()]
-    fn duration(&self) -> f64;
+    /// Backend with fixed event times per type, nothing else is implemented
+    struct EventsOnly(Vec<(f64, EventType)>);
 
-    /// Returns all events of the given type in the given duration.
-    /// t_start is inclusive, t_end is exclusive. If a max_count is given and
-    /// reached, there might be more events in the time span that are not returned.
-    fn events(&self, ty: EventType, t_start: f64, t_end: f64, max_count: usize) -> Vec<f64>;
+    impl Backend for EventsOnly {
+        fn fov(&self) -> Option<(f64, f64, f64)> {
+            None
+        }
 
-    /// Returns the time range of the next encounter of the given type.
-    /// If `t_start` is inside of a block, this block is not returned: only
-    /// blocks **starting** after (or exactly on) `t_start` are considered.
-    /// TODO: EventType should be the first parameter
-    fn encounter(&self, t_start: f64, ty: EventType) -> Option<(f64, f64)>;
+        fn duration(&self) -> f64 {
+            1.0
+        }
+
+        fn events_iter(
+            &self,
+            ty: EventType,
+            t_start: f64,
+            t_end: f64,
+        ) -> Box<dyn Iterator<Item = f64> + '_> {
+            Box::new(
+                self.0
+                    .iter()
+                    .filter(move |(_, t)| EventTypes::from(*t).contains(ty))
+                    .map(|(time, _)| *time)
+                    .filter(move |time| t_start <= *time && *time < t_end),
+            )
+        }
+
+        fn encounters(
+            &self,
+            _ty: EventType,
+            _t_start: f64,
+            _t_end: f64,
+        ) -> Box<dyn Iterator<Item = (f64, f64)> + '_> {
+            Box::new(std::iter::empty())
+        }
+
+        // Doesn't clear the buffers, which `Sequence` must do instead
+        fn sample(&self, _time: &[f64], _samples: &mut SampleVec) {}
+
+        fn integrate(&self, _time: &[f64], _moments: &mut MomentVec) {}
+    }
+
+    /// Backend that doesn't know about triggers: delegates to another
+    /// sequence and drops its trigger samples
+    struct WithoutTriggers(Sequence);
+
+    impl Backend for WithoutTriggers {
+        fn fov(&self) -> Option<(f64, f64, f64)> {
+            self.0.fov()
+        }
+
+        fn duration(&self) -> f64 {
+            self.0.duration()
+        }
+
+        fn events_iter(
+            &self,
+            ty: EventType,
+            t_start: f64,
+            t_end: f64,
+        ) -> Box<dyn Iterator<Item = f64> + '_> {
+            self.0 .0.events_iter(ty, t_start, t_end)
+        }
+
+        fn encounters(
+            &self,
+            ty: EventType,
+            t_start: f64,
+            t_end: f64,
+        ) -> Box<dyn Iterator<Item = (f64, f64)> + '_> {
+            self.0 .0.encounters(ty, t_start, t_end)
+        }
+
+        fn sample(&self, time: &[f64], samples: &mut SampleVec) {
+            self.0 .0.sample(time, samples);
+            samples.trigger = TriggerSampleVec::default();
+        }
+
+        fn integrate(&self, time: &[f64], moments: &mut MomentVec) {
+            self.0 .0.integrate(time, moments);
+        }
+    }
+
+    fn assert_send_sync_clone<T: Send + Sync + Clone>() {}
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn thread_safety() {
+        assert_send_sync_clone::<Sequence>();
+        assert_send_sync::<backend_pulseq::PulseqSequence>();
+        assert_send_sync::<backend_dsv::DsvSequence>();
+    }
 
-    /// Samples the sequence at the given time points
-    fn sample(&self, time: &[f64]) -> Vec<Sample>;
+    #[test]
+    fn events_multi() {
+        let gx = EventType::Gradient(GradientChannel::X);
+        let seq = Sequence::from_backend(EventsOnly(vec![
+            (0.1, EventType::RfPulse),
+            (0.3, EventType::RfPulse),
+            (0.2, EventType::Adc),
+            (0.3 + 1e-12, EventType::Adc),
+            (0.0, gx),
+            (0.3, gx),
+            (0.9, gx),
+        ]));
 
-    /// Integrates over the n-1 time intervalls given by the list of n time points.
-    fn integrate(&self, time: &[f64]) -> Vec<Moment>;
+        let events = seq.events_multi(&EventType::ALL, 0.0, 0.5);
+        let times: Vec<f64> = events.iter().map(|(t, _)| *t).collect();
+        check!(times == [0.0, 0.1, 0.2, 0.3]);
+
+        let merged = events[3].1;
+        check!(merged.contains(EventType::RfPulse));
+        check!(merged.contains(EventType::Adc));
+        check!(merged.contains(gx));
+        check!(!merged.contains(EventType::Gradient(GradientChannel::Y)));
+
+        // Only the requested types are returned
+        check!(seq.events_multi(&[EventType::Adc], 0.0, 0.5).len() == 2);
+    }
+
+    #[test]
+    fn into_clears_buffers() {
+        let gre = load_pulseq_from_str(include_str!("../examples/gre.seq")).unwrap();
+        let mut samples = gre.sample(&[0.0, 1e-3]);
+        let mut moments = gre.integrate(&[0.0, 1e-3, 2e-3]);
+
+        let seq = Sequence::from_backend(EventsOnly(Vec::new()));
+        seq.sample_into(&[0.5], &mut samples);
+        seq.integrate_into(&[0.5, 0.6], &mut moments);
+        check!(samples.is_empty());
+        check!(moments.is_empty());
+    }
+
+    #[test]
+    fn missing_trigger_samples() {
+        let gre = load_pulseq_from_str(include_str!("../examples/gre.seq")).unwrap();
+        let seq = Sequence::from_backend(WithoutTriggers(gre));
+        let samples = seq.sample(&[0.0, 1e-3, 2e-3]);
+        check!(samples.len() == 3);
+        check!(samples.trigger.active == [false; 3]);
+        check!(!seq.sample_one(1e-3).trigger.active);
+    }
+
+    #[test]
+    fn adc_readouts() {
+        let seq = load_pulseq_from_str(include_str!("../examples/gre.seq")).unwrap();
+        let readouts: Vec<_> = seq.adc_readouts(0.0, f64::INFINITY).collect();
+        check!(readouts.len() == seq.encounters(EventType::Adc, 0.0, f64::INFINITY).count());
+        check!(readouts[3].index == 3);
+
+        // Every ADC sample maps back to its readout and sample index
+        let (start, end) = seq.encounter(0.0, EventType::Adc).unwrap();
+        let samples = seq.events(EventType::Adc, start, end, usize::MAX);
+        check!(samples.len() == readouts[0].num_samples);
+        for (i, &t) in samples.iter().enumerate() {
+            check!(seq.adc_sample_at(t) == Some((readouts[0], i)));
+        }
+        check!(seq.adc_sample_at(0.0).is_none());
+    }
+
+    #[test]
+    fn estimated_pulse_info() {
+        // Pulseq 1.4 doesn't store usage and center: 10° sinc pulse with 100 us delay
+        let seq = load_pulseq_from_str(include_str!("../examples/gre.seq")).unwrap();
+        let pulse = seq.encounter(0.0, EventType::RfPulse).unwrap();
+        let info = seq.pulse_info(pulse);
+
+        check!(info.estimated);
+        check!(info.usage == PulseUsage::Excitation);
+        check!((info.center - 1.6e-3).abs() < 1e-9);
+        check!((info.isodelay - 1.5e-3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn kspace() {
+        // k = 0 at the excitation center, ADC samples at delay + (i + 0.5) * dwell.
+        // Calculated by hand from the [TRAP] table of gre.seq (areas in Hz/m * s):
+        // - x: prephaser 2 (-558036 Hz/m, 90/820/90 us) and readout 5 (312500 Hz/m, 50 us ramp)
+        // - y: phase encoders 3, 9, 11 and 13 (90/820/90 us)
+        // - z: second half of slice select 1 (444444 Hz/m, 1500 + 70 / 2 us)
+        //   and rephaser 4 (-784163 Hz/m, 130/740/130 us)
+        let seq = load_pulseq_from_str(include_str!("../examples/gre.seq")).unwrap();
+        let ky = [-549451.0, -545158.0, -540865.0, -536573.0].map(|amp| amp * 910e-6);
+        let kz = 444444.0 * 1535e-6 - 784163.0 * 870e-6;
+        let kx = |i: usize| {
+            let t_adc = 50e-6 + (i as f64 + 0.5) * 12.5e-6;
+            -558036.0 * 910e-6 + 312500.0 * (t_adc - 25e-6)
+        };
+
+        let pulses = seq.encounters(EventType::RfPulse, 0.0, f64::INFINITY);
+        for ((pulse, readout), ky) in pulses.zip(seq.adc_readouts(0.0, f64::INFINITY)).zip(ky) {
+            let mut time = vec![seq.pulse_info(pulse).center];
+            time.extend((0..readout.num_samples).map(|i| readout.sample_time(i)));
+            let moments = seq.integrate(&time);
+
+            let mut k = [0.0; 3];
+            for i in 0..moments.len() {
+                k[0] += moments.gradient.x[i];
+                k[1] += moments.gradient.y[i];
+                k[2] += moments.gradient.z[i];
+                check!((k[0] - kx(i)).abs() < 1e-6);
+                check!((k[1] - ky).abs() < 1e-6);
+                check!((k[2] - kz).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn kspace_shaped_gradients() {
+        // Calculated by hand from the shapes: the waveform is linear between the
+        // raster centers (arbitrary) or vertices (extended), k is its exact
+        // integral. The file is pulseq 1.4, so the first and last amplitude of
+        // the triangle on x are extrapolated from the shape, which gives 0.
+        // - x: triangle 0.1 .. 0.9 0.9 .. 0.1 (100000 Hz/m), area 4.95 raster cells,
+        //   then the readout (200000 Hz/m, 100 us ramps, 400 us flat)
+        // - y: extended trapezoid (-50000 Hz/m, vertices 0 30 70 100 us)
+        let seq = load_pulseq_from_str(include_str!("../examples/kspace.seq")).unwrap();
+        let ky = -50000.0 * 70e-6;
+        let kx = |i: usize| {
+            let t = 50e-6 + (i as f64 + 0.5) * 10e-6;
+            let readout = if t < 100e-6 {
+                200000.0 * t * t / (2.0 * 100e-6)
+            } else {
+                200000.0 * (t - 50e-6)
+            };
+            100000.0 * 4.95 * 10e-6 + readout
+        };
+
+        let pulse = seq.encounter(0.0, EventType::RfPulse).unwrap();
+        let readout = seq.adc_readouts(0.0, f64::INFINITY).next().unwrap();
+        let mut time = vec![seq.pulse_info(pulse).center];
+        time.extend((0..readout.num_samples).map(|i| readout.sample_time(i)));
+        let moments = seq.integrate(&time);
+
+        let mut k = [0.0; 3];
+        for i in 0..moments.len() {
+            k[0] += moments.gradient.x[i];
+            k[1] += moments.gradient.y[i];
+            k[2] += moments.gradient.z[i];
+            check!((k[0] - kx(i)).abs() < 1e-6);
+            check!((k[1] - ky).abs() < 1e-6);
+            check!(k[2] == 0.0);
+        }
+        check!(seq.validate().is_empty());
+    }
 }
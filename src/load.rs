@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{backend_dsv, backend_pulseq, Error, Interpolation, Sequence};
+
+/// Options for [`load`]. Every option only applies to the formats it is
+/// relevant for and is ignored by all other backends.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// DSV: number of ADC samples per ADC block. If `None`, samples are
+    /// placed on a 10 us raster.
+    pub dsv_resolution: Option<usize>,
+    /// DSV: reference voltage, required if RF amplitudes are stored in Volt
+    pub dsv_ref_voltage: Option<f64>,
+    /// Pulseq: values of soft delays in seconds by their hint, e.g. `"TE"`.
+    /// Delays that are not set keep the block durations of the file.
+    pub soft_delays: HashMap<String, f64>,
+    /// All formats: how RF, gradient and ADC phase shapes are sampled
+    /// between their samples
+    pub interpolation: Interpolation,
+}
+
+/// Load a sequence without knowing its format. `path` can point to:
+/// - a pulseq `.seq` file
+/// - any of the DSV channel files (`<stem>_RFD.dsv`, `<stem>_GRX.dsv`, ...)
+/// - the DSV stem itself (the path without the `_XXX.dsv` suffix)
+/// - a directory containing exactly one `.seq` file or one DSV sequence
+pub fn load<P: AsRef<Path>>(path: P, options: LoadOptions) -> Result<Sequence, Error> {
+    match detect_format(path.as_ref())? {
+        Format::Pulseq(path) => Ok(Sequence(Arc::new(backend_pulseq::PulseqSequence::load(
+            path, &options,
+        )?))),
+        Format::Dsv(stem) => Ok(Sequence(Arc::new(backend_dsv::DsvSequence::load(
+            stem,
+            options.dsv_resolution,
+            options.dsv_ref_voltage,
+            options.interpolation,
+        )?))),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Format {
+    /// Path to the .seq file
+    Pulseq(PathBuf),
+    /// Path to the DSV stem, as expected by `DsvSequence::load`
+    Dsv(PathBuf),
+}
+
+/// Every DSV sequence contains an RFD file, which is used to find it
+const DSV_MARKER: &str = "_RFD.dsv";
+
+fn detect_format(path: &Path) -> Result<Format, Error> {
+    if path.is_dir() {
+        return detect_format_in_dir(path);
+    }
+
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    if extension.is_some_and(|ext| ext.eq_ignore_ascii_case("seq")) {
+        return Ok(Format::Pulseq(path.to_owned()));
+    }
+    if extension.is_some_and(|ext| ext.eq_ignore_ascii_case("dsv")) {
+        // Channel file: strip the `_XXX` channel name from the stem
+        let stem = path.file_stem().and_then(|stem| stem.to_str());
+        return match stem.and_then(|stem| stem.rsplit_once('_')) {
+            Some((stem, _)) => Ok(Format::Dsv(path.with_file_name(stem))),
+            None => Err(Error::UnknownFormat(path.to_owned())),
+        };
+    }
+
+    // Bare DSV stem - resolve it the same way as the DSV loader does
+    if let Some(stem) = path.file_name().and_then(|stem| stem.to_str()) {
+        if path.with_file_name(format!("{stem}{DSV_MARKER}")).is_file() {
+            return Ok(Format::Dsv(path.to_owned()));
+        }
+    }
+
+    if path.exists() {
+        Err(Error::UnknownFormat(path.to_owned()))
+    } else {
+        Err(Error::FileNotFound(path.to_owned()))
+    }
+}
+
+fn detect_format_in_dir(dir: &Path) -> Result<Format, Error> {
+    let entries = std::fs::read_dir(dir).map_err(|_| Error::FileNotFound(dir.to_owned()))?;
+
+    let mut found = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        if let Some(stem) = name.strip_suffix(DSV_MARKER) {
+            found.push(Format::Dsv(dir.join(stem)));
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("seq"))
+        {
+            found.push(Format::Pulseq(path));
+        }
+    }
+
+    match found.len() {
+        0 => Err(Error::UnknownFormat(dir.to_owned())),
+        1 => Ok(found.pop().unwrap()),
+        _ => Err(Error::AmbiguousFormat(dir.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_format, Format};
+    use crate::Error;
+    use assert2::check;
+
+    #[test]
+    fn detect() {
+        let dir = std::env::temp_dir().join(format!("disseqt_detect_{}", rand::random::<u64>()));
+        std::fs::create_dir_all(dir.join("dsv")).unwrap();
+        std::fs::create_dir_all(dir.join("both")).unwrap();
+        std::fs::create_dir_all(dir.join("dotted")).unwrap();
+        for file in [
+            "dsv/proto_RFD.dsv",
+            "dsv/proto_GRX.dsv",
+            "dotted/my.v2_RFD.dsv",
+            "both/a.seq",
+            "both/b_RFD.dsv",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+
+        let seq = dir.join("both/a.seq");
+        let stem = dir.join("dsv/proto");
+        check!(detect_format(&seq).unwrap() == Format::Pulseq(seq.clone()));
+        check!(detect_format(&stem).unwrap() == Format::Dsv(stem.clone()));
+        check!(detect_format(&dir.join("dsv")).unwrap() == Format::Dsv(stem.clone()));
+        check!(detect_format(&dir.join("dsv/proto_GRX.dsv")).unwrap() == Format::Dsv(stem.clone()));
+        check!(let Err(Error::AmbiguousFormat(_)) = detect_format(&dir.join("both")));
+
+        // Dots in the stem are not an extension
+        let dotted = dir.join("dotted/my.v2");
+        check!(detect_format(&dotted).unwrap() == Format::Dsv(dotted.clone()));
+        check!(
+            detect_format(&dir.join("dotted/my.v2_RFD.dsv")).unwrap()
+                == Format::Dsv(dotted.clone())
+        );
+        check!(detect_format(&dir.join("dotted")).unwrap() == Format::Dsv(dotted.clone()));
+        check!(let Err(Error::FileNotFound(_)) = detect_format(&dir.join("missing")));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,29 @@
+/// A single ADC block (readout) and the timing of its samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdcReadout {
+    /// Number of readouts before this one, counted from the sequence start
+    pub index: usize,
+    /// Time of the first sample in seconds
+    pub start: f64,
+    pub num_samples: usize,
+    /// Time between two samples in seconds
+    pub dwell: f64,
+    /// Phase offset in radians
+    pub phase: f64,
+    /// Frequency offset in Hz
+    pub frequency: f64,
+}
+
+impl AdcReadout {
+    /// Time of the sample with the given index, not checked against `num_samples`
+    pub fn sample_time(&self, sample: usize) -> f64 {
+        self.start + sample as f64 * self.dwell
+    }
+
+    /// Index of the sample closest to `t`, or `None` if `t` is more than half
+    /// a dwell time away from all samples of this readout
+    pub fn sample_index(&self, t: f64) -> Option<usize> {
+        let index = ((t - self.start) / self.dwell).round();
+        (index >= 0.0 && index < self.num_samples as f64).then_some(index as usize)
+    }
+}
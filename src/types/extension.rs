@@ -0,0 +1,16 @@
+/// An extension entry attached to a block of a block based sequence format,
+/// like the `[EXTENSIONS]` of pulseq. Gives access to custom extensions that
+/// are not interpreted by disseqt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtensionEvent {
+    /// Start time of the block the extension is attached to
+    pub time: f64,
+    /// Name of the extension type, e.g.: `"LABELSET"` or `"TRIGGERS"`
+    pub name: String,
+    /// Id of the entry in the extension table
+    pub id: u32,
+    /// Numeric payload of the entry. Non-numeric fields are NaN, see `raw`
+    pub data: Vec<f64>,
+    /// Payload of the entry as written in the file
+    pub raw: Vec<String>,
+}
@@ -0,0 +1,138 @@
+/// How the sampled shapes of a sequence (RF, free gradients and all DSV
+/// channels) are evaluated between their samples.
+///
+/// All backends use the same definition of a sample: sample `i` of a shape
+/// with raster `dt` that starts at `t0` describes the raster cell
+/// `[t0 + i * dt, t0 + (i + 1) * dt)` and lies at its center `t0 + (i + 0.5) * dt`.
+/// Outside of the cells of a shape, it is zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    /// Every sample is held from its center to the center of the next one
+    ZeroOrderHold,
+    /// The sample of the raster cell containing `t`, which is the sample with
+    /// the nearest center. This is the shape `integrate` uses for RF pulses.
+    #[default]
+    Nearest,
+    /// Linear interpolation between the centers of neighbouring samples. The
+    /// first and last half cell of a shape are constant. Between the centers,
+    /// this is the shape `integrate` uses for gradients; at the edges it uses
+    /// the gradient's start and end amplitude instead.
+    Linear,
+}
+
+impl Interpolation {
+    /// Returns the two samples to interpolate between and the weight of the
+    /// second one, for the position `x` in raster cells relative to the start
+    /// of a shape with `len` samples. `None` outside of the shape.
+    pub(crate) fn samples(self, x: f64, len: usize) -> Option<(usize, usize, f64)> {
+        if !(0.0..len as f64).contains(&x) {
+            return None;
+        }
+        let last = len - 1;
+
+        Some(match self {
+            Interpolation::Nearest => {
+                let i = (x.floor() as usize).min(last);
+                (i, i, 0.0)
+            }
+            Interpolation::ZeroOrderHold => {
+                let i = ((x - 0.5).floor().max(0.0) as usize).min(last);
+                (i, i, 0.0)
+            }
+            Interpolation::Linear => {
+                let x = x - 0.5;
+                if x <= 0.0 {
+                    (0, 0, 0.0)
+                } else if x >= last as f64 {
+                    (last, last, 0.0)
+                } else {
+                    let i = x.floor() as usize;
+                    (i, i + 1, x - i as f64)
+                }
+            }
+        })
+    }
+
+    /// Evaluates `shape` at the position `x` in raster cells, see `samples`
+    pub(crate) fn sample(self, shape: &[f64], x: f64) -> f64 {
+        self.samples(x, shape.len())
+            .map_or(0.0, |(a, b, w)| lerp(shape[a], shape[b], w))
+    }
+
+    /// Evaluates a complex shape with `len` samples, where `sample(i)` returns
+    /// magnitude and phase (in radians) of sample `i`. Linear interpolation
+    /// is done on the complex values, so that phase wraps and sign changes
+    /// are handled. `None` outside of the shape, see `samples`.
+    pub(crate) fn sample_polar(
+        self,
+        len: usize,
+        x: f64,
+        sample: impl Fn(usize) -> (f64, f64),
+    ) -> Option<(f64, f64)> {
+        let (a, b, w) = self.samples(x, len)?;
+        if a == b || w == 0.0 {
+            return Some(sample(a));
+        }
+
+        let ((mag_a, phase_a), (mag_b, phase_b)) = (sample(a), sample(b));
+        let re = lerp(mag_a * phase_a.cos(), mag_b * phase_b.cos(), w);
+        let im = lerp(mag_a * phase_a.sin(), mag_b * phase_b.sin(), w);
+        Some((re.hypot(im), im.atan2(re)))
+    }
+}
+
+fn lerp(a: f64, b: f64, w: f64) -> f64 {
+    a + w * (b - a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interpolation;
+    use assert2::check;
+
+    const SHAPE: [f64; 3] = [1.0, 2.0, 4.0];
+
+    #[test]
+    fn modes() {
+        let sample = |mode: Interpolation, x: f64| mode.sample(&SHAPE, x);
+
+        // Cells are [i, i + 1), centers at i + 0.5
+        check!(sample(Interpolation::Nearest, 0.0) == 1.0);
+        check!(sample(Interpolation::Nearest, 1.9) == 2.0);
+        check!(sample(Interpolation::ZeroOrderHold, 1.4) == 1.0);
+        check!(sample(Interpolation::ZeroOrderHold, 1.5) == 2.0);
+        check!(sample(Interpolation::Linear, 0.2) == 1.0);
+        check!(sample(Interpolation::Linear, 1.0) == 1.5);
+        check!(sample(Interpolation::Linear, 2.0) == 3.0);
+        check!(sample(Interpolation::Linear, 2.9) == 4.0);
+
+        // Zero outside of the shape
+        for mode in [
+            Interpolation::Nearest,
+            Interpolation::ZeroOrderHold,
+            Interpolation::Linear,
+        ] {
+            check!(sample(mode, -0.1) == 0.0);
+            check!(sample(mode, 3.0) == 0.0);
+        }
+    }
+
+    #[test]
+    fn polar() {
+        // Halfway between phase +170° and -170° is 180°, not 0°
+        let phases = [170f64.to_radians(), -170f64.to_radians()];
+        let (magnitude, phase) = Interpolation::Linear
+            .sample_polar(2, 1.0, |i| (1.0, phases[i]))
+            .unwrap();
+        check!((phase.abs() - std::f64::consts::PI).abs() < 1e-12);
+        check!((magnitude - 10f64.to_radians().cos()).abs() < 1e-12);
+
+        // Edges are not extrapolated, which could flip the sign of the magnitude
+        let magnitudes = [1.0, 0.1];
+        let (magnitude, phase) = Interpolation::Linear
+            .sample_polar(2, 1.9, |i| (magnitudes[i], 0.5))
+            .unwrap();
+        check!(magnitude == 0.1);
+        check!(phase == 0.5);
+    }
+}
@@ -0,0 +1,17 @@
+use std::collections::BTreeMap;
+
+/// Values of the sequence labels (`LIN`, `PAR`, `SLC`, `ECO`, `AVG`, ...)
+/// at some point in time. Flags like `NAV` or `REV` are stored as 0 or 1.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Labels(pub BTreeMap<String, i64>);
+
+impl Labels {
+    /// Labels that were never set are 0
+    pub fn get(&self, label: &str) -> i64 {
+        self.0.get(label).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, label: &str, value: i64) {
+        self.0.insert(label.to_owned(), value);
+    }
+}
@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+
+/// General information about the sequence. All fields are optional because
+/// not every file format (or every file) provides them.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Name of the sequence
+    pub name: Option<String>,
+    /// Total duration in seconds, as stated by the file
+    pub total_duration: Option<f64>,
+    /// Echo time in seconds
+    pub te: Option<f64>,
+    /// Repetition time in seconds
+    pub tr: Option<f64>,
+    /// Field of view as stored in the file, which should be in meters
+    pub fov: Option<(f64, f64, f64)>,
+    /// Some files store the FOV in millimeters instead of meters. This flag
+    /// is set if the stored FOV is larger than one meter, which suggests mm.
+    /// The `fov` is not converted.
+    pub fov_assumed_mm: bool,
+    /// Nominal (carrier) frequency of the scanner in Hz
+    pub nominal_frequency: Option<f64>,
+    /// All definitions of the file as raw key-value pairs, including the ones
+    /// parsed into the fields above and any user defined keys.
+    pub definitions: HashMap<String, String>,
+}
+
+/// Native time resolution of the sequence channels in seconds. The stored
+/// samples lie at the centers of the raster cells: sample `i` of a shape that
+/// starts at `t0` is at `t0 + (i + 0.5) * raster`, see [`crate::Interpolation`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rasters {
+    pub rf: f64,
+    pub gradient_x: f64,
+    pub gradient_y: f64,
+    pub gradient_z: f64,
+    pub adc: f64,
+    /// Raster of the block durations, only exists for block based formats
+    pub block: Option<f64>,
+}
@@ -1,21 +1,126 @@
+mod adc_readout;
+mod extension;
+mod interpolation;
+mod labels;
+mod metadata;
+mod pulse_info;
+mod rotation;
 mod scalar_types;
+mod soft_delay;
+mod validation;
 mod vector_types;
 
+pub use adc_readout::AdcReadout;
+pub use extension::ExtensionEvent;
+pub use interpolation::Interpolation;
+pub use labels::Labels;
+pub use metadata::{Metadata, Rasters};
+pub use pulse_info::{PulseInfo, PulseUsage};
+pub use rotation::Rotation;
 pub use scalar_types::*;
+pub use soft_delay::SoftDelay;
+pub use validation::ValidationIssue;
 pub use vector_types::*;
 
 /// Used for Block::Gradient(channel)
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GradientChannel {
     X,
     Y,
     Z,
 }
 
-/// Used to fetch the next POI or block time span of the given type.
-#[derive(Debug, Clone, Copy)]
+/// Used to fetch the next POI or block time span of the given type. New
+/// event types can be added without a major version bump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum EventType {
     RfPulse,
     Adc,
     Gradient(GradientChannel),
+    /// Input (e.g. ECG) or output trigger. Its POIs are the start and end.
+    /// Every trigger is a separate encounter, even if a block has several.
+    /// Where triggers overlap, `sample` returns the one that starts first.
+    Trigger,
+}
+
+impl EventType {
+    /// All event types, e.g. to fetch all POIs with `Sequence::events_multi`
+    pub const ALL: [EventType; 6] = [
+        EventType::RfPulse,
+        EventType::Adc,
+        EventType::Gradient(GradientChannel::X),
+        EventType::Gradient(GradientChannel::Y),
+        EventType::Gradient(GradientChannel::Z),
+        EventType::Trigger,
+    ];
+}
+
+/// Options for `Sequence::integrate_rf`. The default integrates the pulses
+/// on-resonant, which is what `Sequence::integrate` does.
+///
+/// Pulses are integrated in the frame rotating with the nominal (carrier)
+/// frequency. The effective field of every RF sample is
+/// `(w1 * cos(phase), w1 * sin(phase), dw)`, rotating with the same handedness
+/// as the on-resonant pulse. Off-resonance is only applied while the RF
+/// channel is defined, free precession between pulses is not included.
+#[derive(Debug, Clone, Default)]
+pub struct RfOptions {
+    /// Pulseq: modulate the pulse phase with the frequency offset of the pulse,
+    /// relative to its start. DSV files store the already modulated phase, so
+    /// this has no effect there.
+    pub apply_freq_offset: bool,
+    /// Unit: `Hz` - off-resonance of the spin, e.g. for fat or B0 inhomogeneity
+    pub off_resonance: f64,
+    /// Unit: `m` - position of the spin. Adds the off-resonance caused by the
+    /// gradients that are played out during the pulse.
+    pub position: Option<(f64, f64, f64)>,
+}
+
+impl RfOptions {
+    /// True if these options don't change the result of the integration
+    pub fn is_on_resonant(&self) -> bool {
+        !self.apply_freq_offset && self.off_resonance == 0.0 && self.position.is_none()
+    }
+}
+
+/// A set of event types, used to tag which channels produced a merged POI.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EventTypes {
+    pub rf_pulse: bool,
+    pub adc: bool,
+    pub gradient_x: bool,
+    pub gradient_y: bool,
+    pub gradient_z: bool,
+    pub trigger: bool,
+}
+
+impl EventTypes {
+    fn flag(&mut self, ty: EventType) -> &mut bool {
+        match ty {
+            EventType::RfPulse => &mut self.rf_pulse,
+            EventType::Adc => &mut self.adc,
+            EventType::Gradient(GradientChannel::X) => &mut self.gradient_x,
+            EventType::Gradient(GradientChannel::Y) => &mut self.gradient_y,
+            EventType::Gradient(GradientChannel::Z) => &mut self.gradient_z,
+            EventType::Trigger => &mut self.trigger,
+        }
+    }
+
+    pub fn contains(&self, ty: EventType) -> bool {
+        let mut types = *self;
+        *types.flag(ty)
+    }
+
+    pub fn insert(&mut self, ty: EventType) {
+        *self.flag(ty) = true;
+    }
+}
+
+impl From<EventType> for EventTypes {
+    fn from(ty: EventType) -> Self {
+        let mut types = Self::default();
+        types.insert(ty);
+        types
+    }
 }
@@ -0,0 +1,26 @@
+/// Purpose of an RF pulse, as stored in pulseq 1.5 files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum PulseUsage {
+    Excitation,
+    Refocusing,
+    Inversion,
+    Saturation,
+    Preparation,
+    Other,
+    #[default]
+    Undefined,
+}
+
+/// Usage and timing of an RF pulse, see [`crate::Sequence::pulse_info`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PulseInfo {
+    pub usage: PulseUsage,
+    /// Time of the effective rotation of the pulse in seconds
+    pub center: f64,
+    /// Duration from `center` to the end of the pulse
+    pub isodelay: f64,
+    /// `true` if the file doesn't store this information and it was
+    /// estimated from the pulse shape and flip angle instead
+    pub estimated: bool,
+}
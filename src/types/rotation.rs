@@ -0,0 +1,184 @@
+use std::ops::Mul;
+
+/// A rotation in 3D space, stored as unit quaternion `w + x*i + y*j + z*k`.
+/// RF pulses rotate the magnetization around an axis in the transverse plane:
+/// a pulse with flip angle `angle` and phase `phase` is `Rotation::new(angle, phase)`.
+///
+/// Rotations are composed like matrices: `b * a` first applies `a`, then `b`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Rotation {
+    pub const IDENTITY: Self = Self {
+        w: 1.0,
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    /// Right-handed rotation by `angle` around the transverse axis
+    /// `(cos(phase), sin(phase), 0)`, as applied by an RF pulse.
+    pub fn new(angle: f64, phase: f64) -> Self {
+        let (sin, cos) = (0.5 * angle).sin_cos();
+        Self {
+            w: cos,
+            x: sin * phase.cos(),
+            y: sin * phase.sin(),
+            z: 0.0,
+        }
+    }
+
+    /// Right-handed rotation by `angle` around an arbitrary axis, which does
+    /// not need to be normalized. Returns the identity for a zero axis.
+    pub fn from_axis_angle(axis: [f64; 3], angle: f64) -> Self {
+        let norm = (axis[0] * axis[0] + axis[1] * axis[1] + axis[2] * axis[2]).sqrt();
+        if norm == 0.0 {
+            return Self::IDENTITY;
+        }
+        let (sin, cos) = (0.5 * angle).sin_cos();
+        Self {
+            w: cos,
+            x: sin * axis[0] / norm,
+            y: sin * axis[1] / norm,
+            z: sin * axis[2] / norm,
+        }
+    }
+
+    /// The rotation that undoes this one
+    pub fn inverse(&self) -> Self {
+        Self {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+
+    /// Rescale to a unit quaternion, removing errors that build up when
+    /// composing many rotations
+    pub fn normalized(&self) -> Self {
+        let norm = (self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z).sqrt();
+        Self {
+            w: self.w / norm,
+            x: self.x / norm,
+            y: self.y / norm,
+            z: self.z / norm,
+        }
+    }
+
+    /// Rotate the vector `v`, e.g. the magnetization `[x, y, z]`
+    pub fn apply(&self, v: [f64; 3]) -> [f64; 3] {
+        // v' = v + 2w (q x v) + 2 q x (q x v), with q = (x, y, z)
+        let q = [self.x, self.y, self.z];
+        let t = cross(q, v).map(|x| 2.0 * x);
+        let qt = cross(q, t);
+        [
+            v[0] + self.w * t[0] + qt[0],
+            v[1] + self.w * t[1] + qt[1],
+            v[2] + self.w * t[2] + qt[2],
+        ]
+    }
+
+    /// Total rotation angle in the range [0, pi]
+    pub fn angle(&self) -> f64 {
+        let sin = (self.x * self.x + self.y * self.y + self.z * self.z).sqrt();
+        2.0 * f64::atan2(sin, self.w.abs())
+    }
+
+    /// Normalized rotation axis, pointing in the direction for which the
+    /// rotation by `angle()` is right-handed. `[0, 0, 1]` for the identity.
+    pub fn axis(&self) -> [f64; 3] {
+        let sin = (self.x * self.x + self.y * self.y + self.z * self.z).sqrt();
+        if sin == 0.0 {
+            return [0.0, 0.0, 1.0];
+        }
+        let sign = if self.w < 0.0 { -1.0 } else { 1.0 };
+        [
+            sign * self.x / sin,
+            sign * self.y / sin,
+            sign * self.z / sin,
+        ]
+    }
+}
+
+impl Mul for Rotation {
+    type Output = Rotation;
+
+    /// Hamilton product: `self * rhs` first applies `rhs`, then `self`
+    fn mul(self, rhs: Rotation) -> Rotation {
+        Rotation {
+            w: self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+            x: self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            y: self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            z: self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+        }
+    }
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rotation;
+    use assert2::check;
+    use std::f64::consts::{FRAC_PI_2, PI};
+
+    fn close(a: [f64; 3], b: [f64; 3]) -> bool {
+        a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-12)
+    }
+
+    #[test]
+    fn rf_convention() {
+        // 90° pulse with phase 0 rotates around x: z -> -y
+        check!(close(
+            Rotation::new(FRAC_PI_2, 0.0).apply([0.0, 0.0, 1.0]),
+            [0.0, -1.0, 0.0]
+        ));
+        // 90° pulse with phase 90° rotates around y: z -> x
+        check!(close(
+            Rotation::new(FRAC_PI_2, FRAC_PI_2).apply([0.0, 0.0, 1.0]),
+            [1.0, 0.0, 0.0]
+        ));
+    }
+
+    #[test]
+    fn composition() {
+        for _ in 0..100 {
+            let a = Rotation::new(rand::random::<f64>() * PI, rand::random::<f64>() * 2.0 * PI);
+            let b = Rotation::from_axis_angle(
+                [rand::random(), rand::random(), rand::random()],
+                rand::random::<f64>() * PI,
+            );
+            let v = [rand::random(), rand::random(), rand::random()];
+
+            check!(close((b * a).apply(v), b.apply(a.apply(v))));
+            check!(close((a.inverse() * a).apply(v), v));
+        }
+    }
+
+    #[test]
+    fn angle_and_axis() {
+        let rot = Rotation::from_axis_angle([0.0, 0.0, 2.0], 1.0);
+        check!((rot.angle() - 1.0).abs() < 1e-12);
+        check!(close(rot.axis(), [0.0, 0.0, 1.0]));
+        check!(close(rot.inverse().axis(), [0.0, 0.0, -1.0]));
+        check!(Rotation::IDENTITY.angle() == 0.0);
+    }
+}
@@ -1,4 +1,10 @@
-// TODO: implement From trait for the reverse operation (see vector_types)
+use std::sync::Arc;
+
+use crate::Rotation;
+
+/// Array of channel (amplitude, phase) of a parallel transmit RF pulse.
+/// Reference counted, so that all samples of a pulse can share it.
+pub type Shim = Arc<[(f64, f64)]>;
 
 // sample() types
 
@@ -12,7 +18,7 @@ pub struct RfPulseSample {
     /// Unit: `Hz`
     pub frequency: f64,
     /// Array of channel (amplitude, phase)
-    pub shim: Option<Vec<(f64, f64)>>,
+    pub shim: Option<Shim>,
 }
 
 /// Contains the gradient amplitudes for a single point in time.
@@ -40,12 +46,27 @@ pub struct AdcBlockSample {
     pub frequency: f64,
 }
 
-/// See `RfPulseSample`, `GradientSample` and `AdcBlockSample`
+/// Contains the trigger state for a single point in time.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct TriggerSample {
+    /// Specifies if a trigger is active at this point in time
+    pub active: bool,
+    /// `true` for input triggers (waiting for e.g. an ECG signal),
+    /// `false` for output triggers
+    pub input: bool,
+    /// Trigger channel as stored in the sequence, 0 if no trigger is active
+    pub channel: u32,
+    /// Unit: `s` - full duration of the active trigger
+    pub duration: f64,
+}
+
+/// See `RfPulseSample`, `GradientSample`, `AdcBlockSample` and `TriggerSample`
 #[derive(Default, Debug, Clone)]
 pub struct Sample {
     pub pulse: RfPulseSample,
     pub gradient: GradientSample,
     pub adc: AdcBlockSample,
+    pub trigger: TriggerSample,
 }
 
 // integrate() types
@@ -64,10 +85,13 @@ pub struct GradientMoment {
 /// Resulting flip angle by integrating an RF pulse over some time period.
 #[derive(Default, Debug, Clone, Copy)]
 pub struct RfPulseMoment {
-    /// Unit: `rad`
+    /// Unit: `rad` - flip angle of a relaxed spin
     pub angle: f64,
-    /// Unit: `rad`
+    /// Unit: `rad` - phase of the rotation that flips a relaxed spin
     pub phase: f64,
+    /// Full rotation applied by the pulse, which can be applied to any
+    /// magnetization, not only to a relaxed spin
+    pub rotation: Rotation,
 }
 
 /// See `RfPulseMoment` and `GradientMoment`
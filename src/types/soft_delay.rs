@@ -0,0 +1,18 @@
+/// A pulseq 1.5 soft delay, which lets the scanner UI change e.g. TE or TR.
+/// All blocks using it get the duration `value / factor + offset`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoftDelay {
+    /// Name of the delay as shown to the user, e.g.: `"TE"` or `"TR"`
+    pub hint: String,
+    /// Numeric id of the delay
+    pub num: u32,
+    /// Block duration offset in seconds
+    pub offset: f64,
+    /// Divisor applied to the value
+    pub factor: f64,
+    /// Value that results in the block duration written in the file, taken
+    /// from the first block using the delay
+    pub default: f64,
+    /// Value the sequence was loaded with, see [`crate::LoadOptions::soft_delays`]
+    pub value: f64,
+}
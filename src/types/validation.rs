@@ -0,0 +1,40 @@
+use super::{EventType, GradientChannel};
+
+/// A timing or consistency problem found by [`crate::Sequence::validate`].
+/// Blocks are identified by their id in the file, times are in seconds.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ValidationIssue {
+    /// The block duration is not a multiple of the block raster
+    BlockDurationOffRaster {
+        block: u32,
+        duration: f64,
+        raster: f64,
+    },
+    /// An event ends after its block (`end` is relative to the block start)
+    EventExceedsBlock {
+        block: u32,
+        event: EventType,
+        end: f64,
+        duration: f64,
+    },
+    /// The delay of an event is not a multiple of its raster
+    DelayOffRaster {
+        block: u32,
+        event: EventType,
+        delay: f64,
+        raster: f64,
+    },
+    /// The gradient jumps from `before` to `after` at the block boundary `time`.
+    /// The sequence starts and ends with a gradient of zero.
+    GradientDiscontinuity {
+        time: f64,
+        channel: GradientChannel,
+        before: f64,
+        after: f64,
+    },
+    /// The `TotalDuration` definition differs from the summed block durations.
+    /// Those are the durations written in the file, soft delay values passed
+    /// at load time are not included.
+    TotalDuration { definition: f64, actual: f64 },
+}
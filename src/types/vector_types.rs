@@ -1,50 +1,60 @@
 // sample() types
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct RfPulseSampleVec {
     pub amplitude: Vec<f64>,
     pub phase: Vec<f64>,
     pub frequency: Vec<f64>,
-    pub shim: Vec<Option<Vec<(f64, f64)>>>,
+    pub shim: Vec<Option<Shim>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct GradientSampleVec {
     pub x: Vec<f64>,
     pub y: Vec<f64>,
     pub z: Vec<f64>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct AdcBlockSampleVec {
     pub active: Vec<bool>,
     pub phase: Vec<f64>,
     pub frequency: Vec<f64>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
+pub struct TriggerSampleVec {
+    pub active: Vec<bool>,
+    pub input: Vec<bool>,
+    pub channel: Vec<u32>,
+    pub duration: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct SampleVec {
     pub pulse: RfPulseSampleVec,
     pub gradient: GradientSampleVec,
     pub adc: AdcBlockSampleVec,
+    pub trigger: TriggerSampleVec,
 }
 
 // integrate() types
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct RfPulseMomentVec {
     pub angle: Vec<f64>,
     pub phase: Vec<f64>,
+    pub rotation: Vec<Rotation>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct GradientMomentVec {
     pub x: Vec<f64>,
     pub y: Vec<f64>,
     pub z: Vec<f64>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct MomentVec {
     pub pulse: RfPulseMomentVec,
     pub gradient: GradientMomentVec,
@@ -52,7 +62,10 @@ pub struct MomentVec {
 
 // Convert AoS to SoA
 
-use crate::{Moment, Sample};
+use crate::{
+    AdcBlockSample, GradientMoment, GradientSample, Moment, RfPulseMoment, RfPulseSample, Rotation,
+    Sample, Shim, TriggerSample,
+};
 
 impl From<Vec<Sample>> for SampleVec {
     fn from(value: Vec<Sample>) -> Self {
@@ -72,11 +85,18 @@ impl From<Vec<Sample>> for SampleVec {
             phase: value.iter().map(|s| s.adc.phase).collect(),
             frequency: value.iter().map(|s| s.adc.frequency).collect(),
         };
+        let trigger = TriggerSampleVec {
+            active: value.iter().map(|s| s.trigger.active).collect(),
+            input: value.iter().map(|s| s.trigger.input).collect(),
+            channel: value.iter().map(|s| s.trigger.channel).collect(),
+            duration: value.iter().map(|s| s.trigger.duration).collect(),
+        };
 
         Self {
             pulse,
             gradient,
             adc,
+            trigger,
         }
     }
 }
@@ -86,6 +106,7 @@ impl From<Vec<Moment>> for MomentVec {
         let pulse = RfPulseMomentVec {
             angle: value.iter().map(|s| s.pulse.angle).collect(),
             phase: value.iter().map(|s| s.pulse.phase).collect(),
+            rotation: value.iter().map(|s| s.pulse.rotation).collect(),
         };
         let gradient = GradientMomentVec {
             x: value.iter().map(|s| s.gradient.x).collect(),
@@ -97,6 +118,150 @@ impl From<Vec<Moment>> for MomentVec {
     }
 }
 
+// Convert SoA element back to AoS
+
+impl SampleVec {
+    pub fn get(&self, index: usize) -> Option<Sample> {
+        if index >= self.len() {
+            return None;
+        }
+        Some(Sample {
+            pulse: RfPulseSample {
+                amplitude: self.pulse.amplitude[index],
+                phase: self.pulse.phase[index],
+                frequency: self.pulse.frequency[index],
+                shim: self.pulse.shim[index].clone(),
+            },
+            gradient: GradientSample {
+                x: self.gradient.x[index],
+                y: self.gradient.y[index],
+                z: self.gradient.z[index],
+            },
+            adc: AdcBlockSample {
+                active: self.adc.active[index],
+                phase: self.adc.phase[index],
+                frequency: self.adc.frequency[index],
+            },
+            trigger: TriggerSample {
+                active: self.trigger.active[index],
+                input: self.trigger.input[index],
+                channel: self.trigger.channel[index],
+                duration: self.trigger.duration[index],
+            },
+        })
+    }
+}
+
+impl MomentVec {
+    pub fn get(&self, index: usize) -> Option<Moment> {
+        if index >= self.len() {
+            return None;
+        }
+        Some(Moment {
+            pulse: RfPulseMoment {
+                angle: self.pulse.angle[index],
+                phase: self.pulse.phase[index],
+                rotation: self.pulse.rotation[index],
+            },
+            gradient: GradientMoment {
+                x: self.gradient.x[index],
+                y: self.gradient.y[index],
+                z: self.gradient.z[index],
+            },
+        })
+    }
+}
+
+// push() methods, used by the backends to fill the SoA types without allocating
+
+impl RfPulseSampleVec {
+    pub fn push(&mut self, sample: RfPulseSample) {
+        self.amplitude.push(sample.amplitude);
+        self.phase.push(sample.phase);
+        self.frequency.push(sample.frequency);
+        self.shim.push(sample.shim);
+    }
+}
+
+impl GradientSampleVec {
+    pub fn push(&mut self, sample: GradientSample) {
+        self.x.push(sample.x);
+        self.y.push(sample.y);
+        self.z.push(sample.z);
+    }
+}
+
+impl AdcBlockSampleVec {
+    pub fn push(&mut self, sample: AdcBlockSample) {
+        self.active.push(sample.active);
+        self.phase.push(sample.phase);
+        self.frequency.push(sample.frequency);
+    }
+}
+
+impl TriggerSampleVec {
+    pub fn push(&mut self, sample: TriggerSample) {
+        self.active.push(sample.active);
+        self.input.push(sample.input);
+        self.channel.push(sample.channel);
+        self.duration.push(sample.duration);
+    }
+}
+
+impl RfPulseMomentVec {
+    pub fn push(&mut self, moment: RfPulseMoment) {
+        self.angle.push(moment.angle);
+        self.phase.push(moment.phase);
+        self.rotation.push(moment.rotation);
+    }
+}
+
+impl GradientMomentVec {
+    pub fn push(&mut self, moment: GradientMoment) {
+        self.x.push(moment.x);
+        self.y.push(moment.y);
+        self.z.push(moment.z);
+    }
+}
+
+// clear() methods: keep the allocations so that buffers can be reused
+
+impl SampleVec {
+    pub fn clear(&mut self) {
+        self.pulse.amplitude.clear();
+        self.pulse.phase.clear();
+        self.pulse.frequency.clear();
+        self.pulse.shim.clear();
+        self.gradient.x.clear();
+        self.gradient.y.clear();
+        self.gradient.z.clear();
+        self.adc.active.clear();
+        self.adc.phase.clear();
+        self.adc.frequency.clear();
+        self.trigger.active.clear();
+        self.trigger.input.clear();
+        self.trigger.channel.clear();
+        self.trigger.duration.clear();
+    }
+}
+
+impl RfPulseMomentVec {
+    pub fn clear(&mut self) {
+        self.angle.clear();
+        self.phase.clear();
+        self.rotation.clear();
+    }
+}
+
+impl MomentVec {
+    pub fn clear(&mut self) {
+        self.pulse.clear();
+        self.gradient.x.clear();
+        self.gradient.y.clear();
+        self.gradient.z.clear();
+    }
+}
+
 // len() methods
 
 impl RfPulseSampleVec {
@@ -107,6 +272,10 @@ impl RfPulseSampleVec {
         assert!(len1 == len2 && len2 == len3);
         len1
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl GradientSampleVec {
@@ -117,6 +286,10 @@ impl GradientSampleVec {
         assert!(len1 == len2 && len2 == len3);
         len1
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl AdcBlockSampleVec {
@@ -127,6 +300,25 @@ impl AdcBlockSampleVec {
         assert!(len1 == len2 && len2 == len3);
         len1
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl TriggerSampleVec {
+    pub fn len(&self) -> usize {
+        let len1 = self.active.len();
+        let len2 = self.input.len();
+        let len3 = self.channel.len();
+        let len4 = self.duration.len();
+        assert!(len1 == len2 && len2 == len3 && len3 == len4);
+        len1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl SampleVec {
@@ -134,18 +326,28 @@ impl SampleVec {
         let len1 = self.pulse.len();
         let len2 = self.gradient.len();
         let len3 = self.adc.len();
-        assert!(len1 == len2 && len2 == len3);
+        let len4 = self.trigger.len();
+        assert!(len1 == len2 && len2 == len3 && len3 == len4);
         len1
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl RfPulseMomentVec {
     pub fn len(&self) -> usize {
         let len1 = self.angle.len();
         let len2 = self.phase.len();
-        assert!(len1 == len2);
+        let len3 = self.rotation.len();
+        assert!(len1 == len2 && len2 == len3);
         len1
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl GradientMomentVec {
@@ -156,6 +358,10 @@ impl GradientMomentVec {
         assert!(len1 == len2 && len2 == len3);
         len1
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl MomentVec {
@@ -165,4 +371,8 @@ impl MomentVec {
         assert!(len1 == len2);
         len1
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
@@ -1,4 +1,7 @@
-use std::ops::MulAssign;
+use std::ops::{Add, MulAssign};
+
+use crate::RfPulseMoment;
+pub use crate::Rotation;
 
 pub struct Spin([f64; 3]);
 
@@ -9,12 +12,12 @@ impl Spin {
 
     pub fn angle(&self) -> f64 {
         // Normalize because error can build up during rotations
-        (self.0[2] / self.norm()).acos() as f64
+        (self.0[2] / self.norm()).acos()
     }
 
     pub fn phase(&self) -> f64 {
         // We want the phase of the applied rotation, not of the spin itself
-        let tmp = f64::atan2(self.0[1], self.0[0]) as f64 + std::f64::consts::FRAC_PI_2;
+        let tmp = f64::atan2(self.0[1], self.0[0]) + std::f64::consts::FRAC_PI_2;
         // Map to the range [0, 2*pi]
         if tmp < 0.0 {
             tmp + std::f64::consts::TAU
@@ -28,46 +31,187 @@ impl Spin {
     }
 }
 
-pub struct Rotation([[f64; 3]; 3]);
-
-impl Rotation {
-    pub fn new(angle: f64, phase: f64) -> Self {
-        let angle = angle as f64;
-        let phase = phase as f64;
-        Self([
-            [
-                angle.cos() * phase.sin().powi(2) + phase.cos().powi(2),
-                (1.0 - angle.cos()) * phase.sin() * phase.cos(),
-                angle.sin() * phase.sin(),
-            ],
-            [
-                (1.0 - angle.cos()) * phase.sin() * phase.cos(),
-                angle.cos() * phase.cos().powi(2) + phase.sin().powi(2),
-                -angle.sin() * phase.cos(),
-            ],
-            [
-                -angle.sin() * phase.sin(),
-                angle.sin() * phase.cos(),
-                angle.cos(),
-            ],
-        ])
+impl MulAssign<Rotation> for Spin {
+    fn mul_assign(&mut self, rhs: Rotation) {
+        self.0 = rhs.apply(self.0);
     }
 }
 
-impl MulAssign<Rotation> for Spin {
-    fn mul_assign(&mut self, rhs: Rotation) {
-        let x = rhs.0[0][0] * self.0[0] + rhs.0[0][1] * self.0[1] + rhs.0[0][2] * self.0[2];
-        let y = rhs.0[1][0] * self.0[0] + rhs.0[1][1] * self.0[1] + rhs.0[1][2] * self.0[2];
-        let z = rhs.0[2][0] * self.0[0] + rhs.0[2][1] * self.0[1] + rhs.0[2][2] * self.0[2];
-        self.0 = [x, y, z];
+/// Moment of an RF pulse with the given total rotation. The flip angle and
+/// phase are the ones seen by a relaxed spin.
+pub fn rf_moment(rotation: Rotation) -> RfPulseMoment {
+    let rotation = rotation.normalized();
+    let mut spin = Spin::relaxed();
+    spin *= rotation;
+    RfPulseMoment {
+        angle: spin.angle(),
+        phase: spin.phase(),
+        rotation,
+    }
+}
+
+/// Sample type of compressed shapes. Pulseq stores them as floats, DSV as
+/// integers; both store run lengths as samples.
+pub trait ShapeSample: Copy + Default + PartialEq + Add<Output = Self> {
+    /// The sample as repetition count, `None` if it is negative or not an integer
+    fn count(self) -> Option<usize>;
+}
+
+impl ShapeSample for i64 {
+    fn count(self) -> Option<usize> {
+        usize::try_from(self).ok()
+    }
+}
+
+impl ShapeSample for f64 {
+    fn count(self) -> Option<usize> {
+        (self >= 0.0 && self.fract() == 0.0).then_some(self as usize)
     }
 }
 
+/// Decompresses a shape stored as run length encoded derivative, which is
+/// used by pulseq and DSV files: two equal samples `x x` are followed by the
+/// number of additional repetitions of `x`. Returns the number of
+/// decompressed samples as error if it doesn't match `num_samples`
+pub fn decompress_shape<T: ShapeSample>(
+    samples: &[T],
+    num_samples: usize,
+) -> Result<Vec<T>, usize> {
+    // First, decompress into the deriviate of the shape
+    let mut deriv = Vec::with_capacity(num_samples);
+
+    // The two samples before the current one, to detect RLE
+    let mut a = None;
+    let mut b = None;
+    // After a detected RLE, skip the RLE check for two samples
+    let mut skip = 0;
+
+    for &sample in samples {
+        match (a, b) {
+            (Some(a), Some(b)) if a == b && skip == 0 => {
+                skip = 2;
+                // Negative or too large repetition counts can't result in a valid shape
+                let count = sample.count().ok_or(deriv.len())?;
+                if deriv.len().saturating_add(count) > num_samples {
+                    return Err(deriv.len().saturating_add(count));
+                }
+                deriv.extend(std::iter::repeat_n(b, count));
+            }
+            _ => {
+                if skip > 0 {
+                    skip -= 1;
+                }
+                deriv.push(sample);
+            }
+        }
+
+        a = b;
+        b = Some(sample);
+    }
+
+    if deriv.len() != num_samples {
+        return Err(deriv.len());
+    }
+
+    // Then, do a cumultative sum to get the shape
+    Ok(deriv
+        .into_iter()
+        .scan(T::default(), |acc, x| {
+            *acc = *acc + x;
+            Some(*acc)
+        })
+        .collect())
+}
+
+/// Start and end value of a gradient shape that is sampled at the raster
+/// centers, as pulseq 1.4 defines them: the first and last half cell continue
+/// the slope of the first and last two samples. Pulseq 1.5 files store them.
+pub fn extrapolated_edges(shape: &[f64]) -> (f64, f64) {
+    match shape.len() {
+        0 => (0.0, 0.0),
+        1 => (shape[0], shape[0]),
+        n => (
+            1.5 * shape[0] - 0.5 * shape[1],
+            1.5 * shape[n - 1] - 0.5 * shape[n - 2],
+        ),
+    }
+}
+
+/// Exact integral of a gradient shape from `x_start` to `x_end`, both in
+/// raster cells. The gradient is piecewise linear through its start value,
+/// the samples at the raster centers and its end value. The result is in
+/// units of the raster, so it has to be multiplied with the raster time.
+pub fn integrate_grad_shape(shape: &[f64], edges: (f64, f64), x_start: f64, x_end: f64) -> f64 {
+    let len = shape.len() as f64;
+    let (x_start, x_end) = (x_start.max(0.0), x_end.min(len));
+    if shape.is_empty() || x_start >= x_end {
+        return 0.0;
+    }
+    let last = shape.len() - 1;
+    let value = |x: f64| {
+        if x <= 0.5 {
+            edges.0 + (shape[0] - edges.0) * 2.0 * x
+        } else if x >= len - 0.5 {
+            shape[last] + (edges.1 - shape[last]) * 2.0 * (x - len + 0.5)
+        } else {
+            let i = ((x - 0.5).floor() as usize).min(last - 1);
+            let w = x - 0.5 - i as f64;
+            shape[i] + w * (shape[i + 1] - shape[i])
+        }
+    };
+
+    // The gradient is linear between all sample centers inside of the
+    // integration window, so the trapezoidal rule is exact
+    let first_center = (x_start - 0.5).ceil().max(0.0) as usize;
+    let centers = (first_center..shape.len())
+        .map(|i| i as f64 + 0.5)
+        .take_while(|&x| x < x_end);
+    let mut integrated = 0.0;
+    let mut prev = (x_start, value(x_start));
+    for x in centers.chain(std::iter::once(x_end)) {
+        let current = (x, value(x));
+        integrated += 0.5 * (prev.1 + current.1) * (current.0 - prev.0);
+        prev = current;
+    }
+
+    integrated
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Rotation, Spin};
+    use super::{extrapolated_edges, integrate_grad_shape, Rotation, Spin};
     use assert2::check;
 
+    const SHAPE: [f64; 3] = [1.0, 2.0, 4.0];
+
+    #[test]
+    fn grad_shape() {
+        check!(extrapolated_edges(&SHAPE) == (0.5, 5.0));
+        check!(extrapolated_edges(&[3.0]) == (3.0, 3.0));
+
+        // Half cells to the edges, trapezoids between the centers
+        let edges = extrapolated_edges(&SHAPE);
+        let integrate = |a: f64, b: f64| integrate_grad_shape(&SHAPE, edges, a, b);
+        check!(integrate(0.0, 3.0) == 0.375 + 1.5 + 3.0 + 2.25);
+        check!(integrate(-1.0, 5.0) == integrate(0.0, 3.0));
+        check!(integrate(1.0, 2.0) == 0.5 * (1.5 + 2.0) * 0.5 + 0.5 * (2.0 + 3.0) * 0.5);
+        check!(integrate(2.0, 1.0) == 0.0);
+
+        // Stored edges that differ from the extrapolation
+        let stored = integrate_grad_shape(&SHAPE, (0.0, 0.0), 0.0, 3.0);
+        check!(stored == 0.25 + 1.5 + 3.0 + 1.0);
+        check!(integrate_grad_shape(&[3.0], (1.0, 2.0), 0.0, 1.0) == 2.0 * 0.5 + 2.5 * 0.5);
+
+        // Matches a fine numerical integration
+        let (a, b) = (0.3, 2.7);
+        let steps = 100_000;
+        let dx = (b - a) / steps as f64;
+        let numerical: f64 = (0..steps)
+            .map(|i| integrate(a + i as f64 * dx, a + (i + 1) as f64 * dx))
+            .sum();
+        check!((integrate(a, b) - numerical).abs() < 1e-9);
+    }
+
     #[test]
     fn random_rot() {
         for _ in 0..1000 {